@@ -0,0 +1,193 @@
+//! A catalog of every backup made by the user, kept in an SQLite database within the user's app
+//! data directory.
+
+use std::path::{Path, PathBuf};
+use rusqlite::{params, Connection};
+use crate::{Error, E_STR};
+
+pub const CATALOG_DB: &str = "catalog.db";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS backups (
+        id          INTEGER PRIMARY KEY,
+        source      TEXT NOT NULL,
+        destination TEXT NOT NULL,
+        size        INTEGER NOT NULL,
+        hash        TEXT NOT NULL,
+        time        INTEGER NOT NULL,
+        host        TEXT NOT NULL,
+        user        TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS backups_source ON backups (source);
+    CREATE INDEX IF NOT EXISTS backups_time ON backups (time);";
+
+/// A single recorded backup event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub size: u64,
+    pub hash: String,
+    /// Local time, formatted as `YYYY-MM-DD HH:MM:SS`
+    pub time: String,
+    pub host: String,
+    pub user: String
+}
+
+/// Restricts a catalog query. Dates are parsed by SQLite in local time, e.g. `2024-01-31` or
+/// `2024-01-31 12:00`.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only sources that start with this path
+    pub prefix: Option<PathBuf>,
+    /// Only backups made at or after this date
+    pub since: Option<String>,
+    /// Only backups made before this date
+    pub until: Option<String>
+}
+
+pub struct Catalog {
+    path: PathBuf,
+    conn: Connection
+}
+
+impl Catalog {
+    /// Opens the catalog at `path`, creating it if necessary.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)
+            .map_err(|e| Error::catalog(path, e))?;
+
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| Error::catalog(path, e))?;
+
+        Ok(Self { path: path.to_path_buf(), conn })
+    }
+
+    /// Opens the catalog within the user's app data directory.
+    pub fn user() -> Result<Self, Error> {
+        let app_data_dir = crate::os::user_app_data_dir(true, crate::BAK9.into())
+            .map_err(|e| Error::Generic(e.to_string()))?;
+
+        Self::open(&app_data_dir.join(CATALOG_DB))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records a backup of `source` that was written to `destination`.
+    pub fn record(&self, source: &Path, destination: &Path) -> Result<(), Error> {
+        let metadata = source.metadata()
+            .map_err(|e| Error::io(crate::IoOp::Read, source, e))?;
+
+        self.conn.execute(
+            "INSERT INTO backups (source, destination, size, hash, time, host, user)
+                VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'), ?5, ?6)",
+            params![
+                source.to_str().expect(E_STR),
+                destination.to_str().expect(E_STR),
+                metadata.len() as i64,
+                crate::hash_file(source)?,
+                host(),
+                user()
+            ])
+            .map_err(|e| Error::catalog(&self.path, e))?;
+
+        Ok(())
+    }
+
+    /// Lists all recorded backups that match the filter, oldest first. A prefix matches whole path
+    /// components, and is compared exactly rather than with `LIKE`, which ignores case.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Entry>, Error> {
+        let since = filter.since.as_deref().map(|date| self.parse_date(date)).transpose()?;
+        let until = filter.until.as_deref().map(|date| self.parse_date(date)).transpose()?;
+        // the root directory is the only prefix with a trailing separator
+        let prefix = filter.prefix.as_deref().map(|prefix| prefix.to_str().expect(E_STR).trim_end_matches('/'));
+
+        let mut statement = self.conn.prepare(
+            "SELECT source, destination, size, hash, datetime(time, 'unixepoch', 'localtime'), host, user
+                FROM backups
+                WHERE (?1 IS NULL OR source = ?1 OR substr(source, 1, length(?1) + 1) = ?1 || '/')
+                    AND (?2 IS NULL OR time >= ?2)
+                    AND (?3 IS NULL OR time < ?3)
+                ORDER BY time, id")
+            .map_err(|e| Error::catalog(&self.path, e))?;
+
+        let rows = statement.query_map(params![prefix, since, until], |row| {
+                Ok(Entry {
+                    source: PathBuf::from(row.get::<_, String>(0)?),
+                    destination: PathBuf::from(row.get::<_, String>(1)?),
+                    size: row.get::<_, i64>(2)? as u64,
+                    hash: row.get(3)?,
+                    time: row.get(4)?,
+                    host: row.get(5)?,
+                    user: row.get(6)?
+                })
+            })
+            .map_err(|e| Error::catalog(&self.path, e))?;
+
+        rows.collect::<Result<Vec<Entry>, _>>()
+            .map_err(|e| Error::catalog(&self.path, e))
+    }
+
+    /// Converts a local date into unix time, using SQLite's date parsing.
+    fn parse_date(&self, date: &str) -> Result<i64, Error> {
+        self.conn.query_row("SELECT CAST(strftime('%s', ?1, 'utc') AS INTEGER)", params![date], |row| row.get::<_, Option<i64>>(0))
+            .map_err(|e| Error::catalog(&self.path, e))?
+            .ok_or_else(|| Error::Generic(format!("Invalid date: {date}")))
+    }
+}
+
+fn host() -> String {
+    hostname::get()
+        .map(|host| host.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let dir = crate::test_dir("test_catalog_query");
+        let db = dir.join(CATALOG_DB);
+
+        let source = dir.join("source.txt");
+        std::fs::write(&source, "LINE 1").unwrap();
+
+        let catalog = Catalog::open(&db).unwrap();
+        catalog.record(&source, &dir.join("source.txt.bak")).unwrap();
+
+        let entries = catalog.query(&Filter::default()).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(source, entries[0].source);
+        assert_eq!(6, entries[0].size);
+        assert_eq!(crate::hash_file(&source).unwrap(), entries[0].hash);
+
+        let filter = Filter { prefix: Some(dir.join("other")), ..Default::default() };
+        assert!(catalog.query(&filter).unwrap().is_empty());
+
+        // only whole path components match
+        let filter = Filter { prefix: Some(dir.join("source")), ..Default::default() };
+        assert!(catalog.query(&filter).unwrap().is_empty());
+        for prefix in [dir.clone(), source.clone(), PathBuf::from("/")] {
+            let filter = Filter { prefix: Some(prefix), ..Default::default() };
+            assert_eq!(1, catalog.query(&filter).unwrap().len());
+        }
+
+        let filter = Filter { since: Some("2000-01-01".to_string()), until: Some("2000-01-02".to_string()), ..Default::default() };
+        assert!(catalog.query(&filter).unwrap().is_empty());
+
+        let filter = Filter { since: Some("not a date".to_string()), ..Default::default() };
+        assert!(catalog.query(&filter).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,47 +1,283 @@
-use std::path::PathBuf;
+use std::{ffi::OsString, path::{Path, PathBuf}};
 use clap::{Parser, Subcommand};
 
-use crate::{PathExt, E_STR};
+use crate::{crypt::Encryption, store::Archive, PathExt, E_STR};
+
+/// FILE, when backing up stdin.
+pub const STDIN: &str = "-";
+
+const DEFAULT_NUM: u8 = 10;
+const DEFAULT_DEBOUNCE: u64 = 500;
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 pub struct Cli {
     #[command(subcommand)]
     pub subcommand: Option<Command>,
 
-    #[arg(value_parser = validate_file)]
-    pub file: PathBuf,
+    #[arg(value_parser = validate_file_or_stdin, required = true,
+        help = "The file to back up, or '-' to back up stdin under --name")]
+    pub file: Option<PathBuf>,
 
     #[arg(value_parser = validate_dir, help = "[default: Same directory as FILE. '-': The user's app data directory]")]
     pub dir: Option<PathBuf>,
 
+    #[arg(long, value_parser = validate_name, help = "The name to back up stdin under, when FILE is '-'")]
+    pub name: Option<String>,
+
     #[arg(short, value_parser = clap::value_parser!(u8).range(1..),
-        default_value_t = 10, help = "Number of backups to keep before pruning")]
+        default_value_t = DEFAULT_NUM, help = "Number of backups to keep before pruning")]
     pub num: u8,
 
     #[arg(short, help = "Force the operation without confirmation")]
     pub force: bool,
 
     #[arg(short, help = "Suppress all output")]
-    pub quiet: bool
+    pub quiet: bool,
+
+    #[arg(long, value_enum, help = "Keep all backups of FILE in a single archive")]
+    pub archive: Option<Archive>,
+
+    #[arg(long, value_name = "RECIPIENT",
+        help = "Encrypt backups to an age public key (age1...), or with $BAK9_PASSPHRASE if 'passphrase'")]
+    pub encrypt: Option<Encryption>,
+
+    #[arg(long, value_name = "FILE", global = true, help = "An age identity file to decrypt encrypted backups with")]
+    pub identity: Option<PathBuf>,
+
+    #[arg(short, long, value_name = "NOTE", help = "Attach NOTE to the new backup, to select it by as @NOTE")]
+    pub message: Option<String>,
+
+    #[arg(long, help = "Record the backup in the user's catalog")]
+    pub catalog: bool,
+
+    #[arg(long, help = "Offer to link the backup history of a moved FILE on its first backup")]
+    pub link: bool,
+
+    #[arg(long, group = "symlinks", help = "Back up the file that a symlinked FILE points to, beside that file [default]")]
+    pub follow: bool,
+
+    #[arg(long, group = "symlinks", help = "Back up a symlinked FILE as a link, rather than the file it points to")]
+    pub no_dereference: bool,
+
+    #[arg(long, group = "symlinks", help = "Back up the file that a symlinked FILE points to, beside the link")]
+    pub beside_link: bool,
+
+    #[arg(long, help = "A command to show differences with, e.g. 'meld'")]
+    pub diff_tool: Option<String>,
+
+    #[arg(long, global = true, help = "Print the renames, deletes and copies that would be performed, without performing them")]
+    pub dry_run: bool,
+
+    #[arg(long, help = "Move pruned backups into the user's trash, rather than deleting them")]
+    pub trash: bool,
+
+    #[arg(long, global = true, help = "Delete backups with 'rm' permanently, rather than moving them into the user's trash")]
+    pub no_trash: bool,
+
+    #[arg(long, overrides_with = "no_wait", help = "Wait for another backup of FILE to finish [default]")]
+    pub wait: bool,
+
+    #[arg(long, overrides_with = "wait", help = "Fail if another backup of FILE is in progress")]
+    pub no_wait: bool,
+
+    #[arg(short, long, help = "Back up FILE every time it changes, until interrupted")]
+    pub watch: bool,
+
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_DEBOUNCE, global = true,
+        help = "Milliseconds without further changes before a watched FILE is backed up")]
+    pub debounce: u64
 }
 
+/// The same defaults as when parsed without options, with neither FILE nor a command.
+impl Default for Cli {
+    fn default() -> Self {
+        Self {
+            subcommand: None,
+            file: None,
+            dir: None,
+            name: None,
+            num: DEFAULT_NUM,
+            force: false,
+            quiet: false,
+            archive: None,
+            encrypt: None,
+            identity: None,
+            message: None,
+            catalog: false,
+            link: false,
+            follow: false,
+            no_dereference: false,
+            beside_link: false,
+            diff_tool: None,
+            dry_run: false,
+            trash: false,
+            no_trash: false,
+            wait: false,
+            no_wait: false,
+            watch: false,
+            debounce: DEFAULT_DEBOUNCE
+        }
+    }
+}
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     #[command(name = "ls", about = "List all backups of FILE in DIR")]
-    List,
+    List {
+        #[arg(short, long, help = "List every file with backups in the user's app data directory")]
+        all: bool
+    },
     #[command(name = "rm", about = "Deletes all backups of FILE in DIR")]
     Wipe, 
     #[command(name = "diff", about = "Shows the differences between FILE and bak.N in DIR")]
     Diff {
-        #[arg(default_value_t = 0, help = "The .bak.N index to compare FILE with")]
-        index: u8,
+        #[arg(default_value_t = Selector::Index(0), help = "The .bak.N index, or @NOTE, of the backup to compare FILE with")]
+        index: Selector,
+    },
+    #[command(name = "restore", about = "Overwrites FILE with bak.N in DIR")]
+    Restore {
+        #[arg(default_value_t = Selector::Index(0), help = "The .bak.N index, or @NOTE, of the backup to restore FILE from")]
+        index: Selector,
+    },
+    #[command(name = "show", visible_alias = "cat", about = "Writes the contents of bak.N in DIR to stdout")]
+    Show {
+        #[arg(default_value_t = Selector::Index(0), help = "The .bak.N index, or @NOTE, of the backup to show")]
+        index: Selector,
+    },
+    #[command(name = "grep", about = "Searches every backup of FILE, beside it and in the user's app data directory, for lines that match PATTERN")]
+    Grep {
+        #[arg(help = "The regular expression to search for")]
+        pattern: regex::bytes::Regex,
+        #[arg(long, conflicts_with = "last_seen", help = "Bisect for the oldest backup that PATTERN appears in, assuming it has appeared once")]
+        first_seen: bool,
+        #[arg(long, help = "Bisect for the newest backup that PATTERN appears in, assuming it has vanished once")]
+        last_seen: bool
+    },
+    #[command(name = "log", about = "Shows the history of FILE, beside it and in the user's app data directory, with the changes made in each backup")]
+    Log {
+        #[arg(short, long, help = "Show the full patch of each change")]
+        patch: bool
+    },
+    #[command(name = "mv", about = "Moves the backup history of OLD to NEW, after the file itself was moved")]
+    Move {
+        #[arg(value_parser = validate_moved_file, help = "The previous path of the file")]
+        old: PathBuf,
+        #[arg(value_parser = validate_file, help = "The current path of the file")]
+        new: PathBuf
+    },
+    #[command(name = "edit", about = "Backs up FILE, opens it in $VISUAL or $EDITOR, and then shows the changes made")]
+    Edit,
+    #[command(name = "exec", about = "Backs up FILE and any other FILES, runs CMD, and then shows which files it changed")]
+    Exec {
+        #[arg(value_parser = validate_file, help = "Other files to back up")]
+        files: Vec<PathBuf>,
+        #[arg(long, help = "Restore the changed files if CMD exits with a failure status")]
+        restore_on_failure: bool,
+        #[arg(last = true, required = true, value_name = "CMD", help = "The command to run, after '--'")]
+        command: Vec<String>
+    },
+    #[command(name = "watch", about = "Backs up FILE every time it changes, until interrupted")]
+    Watch,
+    #[command(name = "verify", about = "Warns about backups of FILE that are more permissive than FILE itself")]
+    Verify {
+        #[arg(short, long, help = "Verify the backups of every file in the user's app data directory")]
+        all: bool
+    },
+    #[command(name = "gc", about = "Deletes backups in the user's app data directory whose FILE no longer exists")]
+    Gc,
+    #[command(name = "catalog", about = "Lists all backups recorded in the user's catalog")]
+    Catalog {
+        #[arg(long, help = "Only backups of files under this path")]
+        prefix: Option<PathBuf>,
+        #[arg(long, help = "Only backups made on or after this date (YYYY-MM-DD [HH:MM:SS])")]
+        since: Option<String>,
+        #[arg(long, help = "Only backups made before this date (YYYY-MM-DD [HH:MM:SS])")]
+        until: Option<String>
+    }
+}
+
+/// A backup, selected either by its `.bak.N` index or by the note attached to it, as `@NOTE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Index(u8),
+    Note(String)
+}
+
+impl std::str::FromStr for Selector {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix('@') {
+            Some("") => Err("NOTE is required after '@'".to_string()),
+            Some(note) => Ok(Self::Note(note.to_string())),
+            None => value.parse()
+                .map(Self::Index)
+                .map_err(|e| format!("{e}: {value}"))
+        }
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{index}"),
+            Self::Note(note) => write!(f, "@{note}")
+        }
     }
 }
 
 impl Cli {
+    /// Whether FILE is `-`, to back up stdin under NAME.
+    pub fn is_stdin(&self) -> bool {
+        self.file.as_deref() == Some(Path::new(STDIN))
+    }
+
+    /// FILE, which is required by every command that operates on a single file.
+    pub fn file(&self) -> Result<&Path, crate::Error> {
+        self.file.as_deref()
+            .ok_or_else(|| crate::Error::Generic("FILE is required".to_string()))
+    }
+
+    /// Resolves FILE, any other FILES to `exec` and NEW to `mv` if they are symlinks, unless they
+    /// are to be backed up as links or beside the link. Returns each link that was followed, along
+    /// with the file that it points to.
+    pub fn follow_symlinks(&mut self) -> Result<Vec<(PathBuf, PathBuf)>, crate::Error> {
+        // following is the default, unless links are to be backed up some other way
+        if !self.follow && (self.no_dereference || self.beside_link) {
+            return Ok(Vec::new())
+        }
+
+        let mut followed = Vec::new();
+        let mut follow = |file: &mut PathBuf| -> Result<(), crate::Error> {
+            if file.is_symlink() {
+                let target = file.canonicalize()
+                    .map_err(|e| crate::Error::io(crate::IoOp::Read, file, e))?;
+                followed.push((std::mem::replace(file, target.clone()), target));
+            }
+
+            Ok(())
+        };
+
+        if let Some(file) = &mut self.file {
+            follow(file)?;
+        }
+
+        match &mut self.subcommand {
+            Some(Command::Exec { files, .. }) => files.iter_mut().try_for_each(&mut follow)?,
+            Some(Command::Move { new, .. }) => follow(new)?,
+            _ => {}
+        }
+
+        Ok(followed)
+    }
+
     pub fn dir(&self) -> PathBuf {
+        self.dir_for(self.file.as_deref())
+    }
+
+    /// DIR, or the directory of `file` if it was not specified.
+    pub fn dir_for(&self, file: Option<&Path>) -> PathBuf {
         match &self.dir {
             Some(dir) => {
                 // handle passing Cli parameters manually
@@ -52,11 +288,67 @@ impl Cli {
                     dir.clone()
                 }
             },
-            None => self.file.parent().expect("Expected parent directory").to_path_buf().clone(),
+            None => file
+                .and_then(|file| file.parent())
+                .expect("Expected parent directory")
+                .to_path_buf(),
         }
     }
 }
 
+/// The command line `args`, with any other FILES given before `exec` moved after it, so that
+/// `bak FILE... exec -- CMD` is parsed as `bak FILE exec FILES... -- CMD`. Otherwise, the second
+/// FILE would be taken as DIR. A directory after FILE is still taken as DIR.
+pub fn exec_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let args: Vec<OsString> = args.into_iter().collect();
+    let command = <Cli as clap::CommandFactory>::command();
+    let takes_value = |arg: Option<&clap::Arg>| arg.is_some_and(|arg| arg.get_action().takes_values());
+
+    // the positions of FILE, and anything else given before the subcommand
+    let mut positionals = Vec::new();
+    let mut i = 1;
+    while let Some(arg) = args.get(i).and_then(|arg| arg.to_str()) {
+        if arg == "--" {
+            return args
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let is_value_next = !long.contains('=')
+                && takes_value(command.get_arguments().find(|a| a.get_long() == Some(long)));
+            i += usize::from(is_value_next);
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // a short option's value is either the rest of the cluster, or the next argument
+            let value_at = shorts.char_indices()
+                .find(|(_, short)| takes_value(command.get_arguments().find(|a| a.get_short() == Some(*short))));
+            i += usize::from(value_at.is_some_and(|(at, short)| at + short.len_utf8() == shorts.len()));
+        } else if arg == "exec" {
+            break
+        } else {
+            positionals.push(i);
+        }
+
+        i += 1;
+    }
+
+    if args.get(i).and_then(|arg| arg.to_str()) != Some("exec") || positionals.len() < 2 {
+        return args
+    }
+
+    let mut files = positionals.split_off(1);
+    if files.first().is_some_and(|&dir| Path::new(&args[dir]).is_dir()) {
+        files.remove(0);
+    }
+
+    let (before, after) = args.split_at(i);
+    before.iter()
+        .enumerate()
+        .filter(|(position, _)| !files.contains(position))
+        .map(|(_, arg)| arg)
+        .chain(after.first())
+        .chain(files.iter().map(|&position| &before[position]))
+        .chain(&after[1..])
+        .cloned()
+        .collect()
+}
+
 fn validate_path(path: &str, filetype: &'static str) -> Result<PathBuf, String> {
     let path = PathBuf::from(path)
         .canonicalize()
@@ -69,18 +361,57 @@ fn validate_path(path: &str, filetype: &'static str) -> Result<PathBuf, String>
     Ok(path)
 }
 
+/// A file, or a symlink to one. Only the parent directory is canonicalized, so that a symlink isn't
+/// resolved until the symlink mode is known.
 fn validate_file(path: &str) -> Result<PathBuf, String> {
-    let path = validate_path(path, "File")?;
+    validate_path(path, "File")?;
+    let path = validate_moved_file(path)?;
+
     if !path.is_file() {
         Err(format!("Source path is not a file: {:?}", path))
     } else if path.filename_str().is_none() {
-        return Err(format!("Invalid source file: {:?}", path))
+        Err(format!("Invalid source file: {:?}", path))
     } else {
         Ok(path)
     }
 }
 
-fn validate_dir(path: &str) -> Result<PathBuf, String> {
+/// A file that may no longer exist, such as the previous path of a moved file.
+fn validate_moved_file(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    let filename = path.file_name()
+        .ok_or_else(|| format!("Invalid source file: {:?}", path))?;
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+
+    match parent.canonicalize() {
+        Ok(parent) => Ok(parent.join(filename)),
+        Err(_) => std::path::absolute(&path)
+            .map_err(|_| format!("Invalid source file: {:?}", path))
+    }
+}
+
+fn validate_file_or_stdin(path: &str) -> Result<PathBuf, String> {
+    if path == STDIN {
+        Ok(PathBuf::from(path))
+    } else {
+        validate_file(path)
+    }
+}
+
+/// A name for stdin's backups, which are named after it like a file.
+fn validate_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        Err(format!("Invalid name: {name:?}"))
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+pub(crate) fn validate_dir(path: &str) -> Result<PathBuf, String> {
     let path = if path == "-" {
         crate::os::user_app_data_dir(true, crate::BAK9.into())
             .map_err(|e| e.to_string())?
@@ -89,7 +420,7 @@ fn validate_dir(path: &str) -> Result<PathBuf, String> {
     };
 
     if !path.is_dir() {
-        return Err(format!("Destination path is not a directory: {:?}", path))
+        Err(format!("Destination path is not a directory: {:?}", path))
     } else {
         Ok(path)
     }
@@ -102,4 +433,27 @@ mod tests {
         use clap::CommandFactory;
         super::Cli::command().debug_assert()
     }
+
+    #[test]
+    fn test_exec_args() {
+        let dir = crate::test_dir("test_cli_exec_args");
+        let dir_str = dir.to_str().unwrap();
+        let args = |args: &[&str]| super::exec_args(args.iter().map(std::ffi::OsString::from))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect::<Vec<_>>();
+
+        // other files are moved after exec, past the values of options
+        assert_eq!(vec!["bak", "-n", "3", "a", "exec", "b", "c", "--", "d"], args(&["bak", "-n", "3", "a", "b", "c", "exec", "--", "d"]));
+        assert_eq!(vec!["bak", "-qn3", "a", "exec", "b", "--", "d"], args(&["bak", "-qn3", "a", "b", "exec", "--", "d"]));
+        assert_eq!(vec!["bak", "--archive", "tar", "a", "exec", "b", "--", "d"], args(&["bak", "--archive", "tar", "a", "b", "exec", "--", "d"]));
+
+        // a directory after FILE is DIR
+        assert_eq!(vec!["bak", "a", dir_str, "exec", "b", "--", "d"], args(&["bak", "a", dir_str, "b", "exec", "--", "d"]));
+
+        // otherwise, the arguments are unchanged
+        for unchanged in [&["bak", "a", "exec", "b", "--", "d"][..], &["bak", "a", "b"], &["bak", "a", "--", "b", "c", "exec"]] {
+            assert_eq!(unchanged.to_vec(), args(unchanged));
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,277 @@
+//! User configuration, loaded from `$XDG_CONFIG_HOME/bak9/config.toml`.
+//!
+//! Every setting is optional and only provides a default. Options given on the command line
+//! always take precedence.
+//!
+//! ```toml
+//! retention = 5               # -n NUM
+//! destination = "mirror"      # "sibling", "mirror" or a directory path
+//! naming = "archive"          # "bak" (.bak.N files) or "archive"
+//! compression = "gzip"        # "none" or "gzip", for archives
+//! diff-tool = "vimdiff -R"
+//! quiet = false
+//! catalog = true
+//! link = false
+//! encrypt = "age1..."         # an age public key, or "passphrase"
+//! identity = "/home/me/.age/key.txt"  # to decrypt with
+//! ```
+
+use std::path::{Path, PathBuf};
+use clap::{parser::ValueSource, ArgMatches};
+use serde::Deserialize;
+use crate::{cli::Cli, crypt::Encryption, store::Archive, Error, BAK9};
+
+/// Points to an alternate config file.
+pub const ENV_BAK9_CONFIG: &str = "BAK9_CONFIG";
+pub const CONFIG_TOML: &str = "config.toml";
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Number of backups to keep before pruning
+    pub retention: Option<u8>,
+    pub destination: Option<Destination>,
+    pub naming: Option<Naming>,
+    pub compression: Option<Compression>,
+    /// A command to show differences with, e.g. `meld`
+    pub diff_tool: Option<String>,
+    pub quiet: Option<bool>,
+    pub catalog: Option<bool>,
+    pub link: Option<bool>,
+    /// Encrypt backups to this age recipient, or with a passphrase
+    pub encrypt: Option<Encryption>,
+    /// An age identity file to decrypt backups with
+    pub identity: Option<PathBuf>
+}
+
+/// Where backups are created by default.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "String")]
+pub enum Destination {
+    /// The same directory as FILE
+    Sibling,
+    /// The user's app data directory
+    Mirror,
+    Dir(PathBuf)
+}
+
+impl From<String> for Destination {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "sibling" => Self::Sibling,
+            "mirror" => Self::Mirror,
+            // the shell would have expanded it on the command line
+            _ => match (value.strip_prefix('~'), std::env::var_os("HOME")) {
+                (Some(path), Some(home)) if path.is_empty() || path.starts_with('/') =>
+                    Self::Dir(PathBuf::from(home).join(path.trim_start_matches('/'))),
+                _ => Self::Dir(PathBuf::from(value))
+            }
+        }
+    }
+}
+
+/// How backup files are named.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Naming {
+    /// Rotating `.bak` and `.bak.N` files
+    Bak,
+    /// A single `.bak9.tar` archive
+    Archive
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    Gzip
+}
+
+impl Config {
+    /// The path of the user's config file: either [ENV_BAK9_CONFIG] or the default location.
+    pub fn user_path() -> Result<PathBuf, Error> {
+        if let Ok(path) = std::env::var(ENV_BAK9_CONFIG) {
+            return Ok(PathBuf::from(path))
+        }
+
+        crate::os::user_app_config_dir(BAK9.into())
+            .map(|dir| dir.join(CONFIG_TOML))
+            .map_err(|e| Error::Generic(e.to_string()))
+    }
+
+    /// Loads the user's config file. A missing file is an empty config, unless it was specified by
+    /// [ENV_BAK9_CONFIG].
+    pub fn user() -> Result<Self, Error> {
+        let path = Self::user_path()?;
+
+        if !path.exists() && std::env::var(ENV_BAK9_CONFIG).is_err() {
+            return Ok(Self::default())
+        }
+
+        Self::load(&path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::io(crate::IoOp::Read, path, e))?;
+
+        Self::parse(&contents)
+            .map_err(|e| Error::config(path, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(contents)
+            .map_err(|e| e.message().to_string())?;
+
+        if config.retention == Some(0) {
+            return Err("retention must be at least 1".to_string())
+        }
+
+        Ok(config)
+    }
+
+    /// The archive format selected by the naming scheme and compression.
+    pub fn archive(&self) -> Option<Archive> {
+        match (self.naming?, self.compression) {
+            (Naming::Bak, _) => None,
+            (Naming::Archive, Some(Compression::Gzip)) => Some(Archive::TarGz),
+            (Naming::Archive, _) => Some(Archive::Tar)
+        }
+    }
+
+    /// Fills in any setting that is not set with the one from `fallback`.
+    pub fn or(self, fallback: Config) -> Config {
+        Config {
+            retention: self.retention.or(fallback.retention),
+            destination: self.destination.or(fallback.destination),
+            naming: self.naming.or(fallback.naming),
+            compression: self.compression.or(fallback.compression),
+            diff_tool: self.diff_tool.or(fallback.diff_tool),
+            quiet: self.quiet.or(fallback.quiet),
+            catalog: self.catalog.or(fallback.catalog),
+            link: self.link.or(fallback.link),
+            encrypt: self.encrypt.or(fallback.encrypt),
+            identity: self.identity.or(fallback.identity)
+        }
+    }
+
+    /// Fills in every option of `cli` that was not given on the command line. A destination
+    /// directory must exist, just as DIR must.
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) -> Result<(), Error> {
+        let is_default = |id: &str| !matches!(matches.value_source(id), Some(ValueSource::CommandLine));
+
+        if let Some(retention) = self.retention.filter(|_| is_default("num")) {
+            cli.num = retention;
+        }
+
+        if cli.dir.is_none() {
+            cli.dir = match &self.destination {
+                Some(Destination::Mirror) => Some(PathBuf::from("-")),
+                Some(Destination::Dir(dir)) => Some(crate::cli::validate_dir(dir.to_str().expect(crate::E_STR))
+                    .map_err(|e| Error::Generic(format!("Invalid destination: {e}")))?),
+                Some(Destination::Sibling) | None => None
+            };
+        }
+
+        if cli.archive.is_none() {
+            cli.archive = self.archive();
+        }
+
+        if cli.diff_tool.is_none() {
+            cli.diff_tool = self.diff_tool.clone();
+        }
+
+        if is_default("quiet") {
+            cli.quiet = self.quiet.unwrap_or(cli.quiet);
+        }
+
+        if is_default("catalog") {
+            cli.catalog = self.catalog.unwrap_or(cli.catalog);
+        }
+
+        if is_default("link") {
+            cli.link = self.link.unwrap_or(cli.link);
+        }
+
+        if cli.encrypt.is_none() {
+            cli.encrypt = self.encrypt.clone();
+        }
+
+        if cli.identity.is_none() {
+            cli.identity = self.identity.clone();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(r#"
+            retention = 5
+            destination = "mirror"
+            naming = "archive"
+            compression = "gzip"
+            diff-tool = "meld"
+            catalog = true
+            encrypt = "passphrase"
+        "#).unwrap();
+
+        assert_eq!(Some(5), config.retention);
+        assert_eq!(Some(Destination::Mirror), config.destination);
+        assert_eq!(Some(Archive::TarGz), config.archive());
+        assert_eq!(Some("meld".to_string()), config.diff_tool);
+        assert_eq!(Some(true), config.catalog);
+        assert_eq!(None, config.quiet);
+        assert_eq!(Some(Encryption::Passphrase), config.encrypt);
+
+        let config = Config::parse(r#"destination = "/var/backups""#).unwrap();
+        assert_eq!(Some(Destination::Dir(PathBuf::from("/var/backups"))), config.destination);
+        assert_eq!(None, config.archive());
+
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        let config = Config::parse(r#"destination = "~/backups""#).unwrap();
+        assert_eq!(Some(Destination::Dir(home.join("backups"))), config.destination);
+
+        assert!(Config::parse("retention = 0").is_err());
+        assert!(Config::parse("retention = 0x100").is_err());
+        assert!(Config::parse("unknown = true").is_err());
+        assert!(Config::parse(r#"encrypt = "age1invalid""#).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let config = Config::parse(r#"
+            retention = 5
+            destination = "mirror"
+            quiet = true
+        "#).unwrap();
+
+        let file = std::env::current_exe().unwrap();
+        let args = ["bak", "-n", "3", file.to_str().unwrap()];
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli, &matches).unwrap();
+
+        assert_eq!(3, cli.num, "command line should take precedence");
+        assert_eq!(Some(PathBuf::from("-")), cli.dir);
+        assert!(cli.quiet);
+
+        // a destination directory is canonicalized, and must exist
+        let dir = file.parent().unwrap();
+        let config = Config::parse(&format!("destination = \"{}/.\"", dir.to_str().unwrap())).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        config.apply(&mut cli, &matches).unwrap();
+        assert_eq!(Some(dir.canonicalize().unwrap()), cli.dir);
+
+        let config = Config::parse(&format!("destination = \"{}\"", dir.join("missing").to_str().unwrap())).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        assert!(config.apply(&mut cli, &matches).is_err());
+    }
+}
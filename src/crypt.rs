@@ -0,0 +1,287 @@
+//! Encryption of backups with [age](https://age-encryption.org), either to a recipient's public
+//! key or with a passphrase.
+//!
+//! Encrypted backups keep their usual names. Since an encrypted backup can't be compared with its
+//! source without the identity, a keyed hash of the source is recorded for each encrypted backup,
+//! which the unchanged check compares instead. The hashes are kept in a manifest beside the
+//! backups, e.g. `foo.conf.bak9.hmac`, or `foo.conf.bak9.tar.hmac` for an archive, which is also
+//! the record of which backups are encrypted: a backup is never recognized as encrypted by its
+//! contents, since the source may be an age encrypted file itself. The hashes are keyed with a
+//! secret that is private to the user, so that they reveal nothing about the contents.
+
+use std::{fs, io::{self, Read, Write}, path::{Path, PathBuf}, str::FromStr};
+use age::secrecy::SecretString;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::{manifest::{self, Manifest}, mirror, os, store::Archive, Error, IoOp, PathExt, BAK9, E_FILENAME};
+
+/// The passphrase to encrypt and decrypt backups with, when encrypting with [PASSPHRASE].
+pub const ENV_BAK9_PASSPHRASE: &str = "BAK9_PASSPHRASE";
+/// Selects passphrase encryption, rather than a recipient.
+pub const PASSPHRASE: &str = "passphrase";
+
+const HASH_KEY: &str = "hash.key";
+pub(crate) const HMAC: &str = "hmac";
+
+/// How backups are encrypted.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Encryption {
+    /// To an age public key, e.g. `age1...`
+    Recipient(String),
+    /// With the passphrase in [ENV_BAK9_PASSPHRASE]
+    Passphrase
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == PASSPHRASE {
+            return Ok(Self::Passphrase)
+        }
+
+        age::x25519::Recipient::from_str(value)
+            .map(|_| Self::Recipient(value.to_string()))
+            .map_err(|e| format!("invalid age recipient {value}: {e}"))
+    }
+}
+
+impl TryFrom<String> for Encryption {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Encryption {
+    fn encryptor(&self) -> Result<age::Encryptor, Error> {
+        match self {
+            Self::Recipient(recipient) => {
+                let recipient = age::x25519::Recipient::from_str(recipient)
+                    .map_err(|e| Error::Generic(format!("Invalid age recipient {recipient}: {e}")))?;
+
+                age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                    .map_err(|e| Error::Generic(e.to_string()))
+            },
+            Self::Passphrase => Ok(age::Encryptor::with_user_passphrase(passphrase()?))
+        }
+    }
+
+    /// Encrypts everything read from `reader` into `writer`.
+    pub fn encrypt(&self, mut reader: impl Read, writer: impl Write) -> io::Result<()> {
+        let mut writer = self.encryptor()
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .wrap_output(writer)?;
+
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Encrypts `source` into `dest`, which is given the same permissions as `source`.
+    pub fn encrypt_file(&self, source: &Path, dest: &Path) -> Result<(), Error> {
+        let reader = fs::File::open(source)
+            .map_err(|e| Error::io(IoOp::Read, source, e))?;
+        let writer = os::create_private_file(dest)
+            .map_err(|e| Error::io(IoOp::Create, dest, e))?;
+
+        self.encrypt(io::BufReader::new(reader), io::BufWriter::new(writer))
+            .and_then(|_| os::sync_file(dest))
+            .map_err(|e| Error::copy(source, dest, e))?;
+
+        let permissions = source.metadata()
+            .map_err(|e| Error::io(IoOp::Read, source, e))?
+            .permissions();
+
+        fs::set_permissions(dest, permissions)
+            .map_err(|e| Error::io(IoOp::Write, dest, e))
+    }
+}
+
+fn passphrase() -> Result<SecretString, Error> {
+    std::env::var(ENV_BAK9_PASSPHRASE)
+        .map(SecretString::from)
+        .map_err(|_| Error::Generic(format!("{ENV_BAK9_PASSPHRASE} is not set")))
+}
+
+/// Decrypts the backup at `path` into a private temporary directory, which is deleted along with
+/// the decrypted file when it is dropped, or if decryption fails. See [decrypt].
+pub fn decrypt_file(path: &Path, identity_file: Option<&Path>) -> Result<os::PrivateTempFile, Error> {
+    let file = fs::File::open(path)
+        .map_err(|e| Error::io(IoOp::Read, path, e))?;
+    let mut reader = decrypt(io::BufReader::new(file), path, identity_file)?;
+
+    let (decrypted, mut writer) = os::PrivateTempFile::create(path.filename_str().expect(E_FILENAME))
+        .map_err(|e| Error::io(IoOp::Create, path, e))?;
+    io::copy(&mut reader, &mut writer)
+        .map_err(|e| Error::io(IoOp::Write, decrypted.path(), e))?;
+
+    Ok(decrypted)
+}
+
+/// Decrypts `reader`, the contents of the backup at `path`, as it is read. Decrypts with the
+/// identities in `identity_file`, or otherwise with [ENV_BAK9_PASSPHRASE].
+pub fn decrypt<'a>(reader: impl io::Read + 'a, path: &Path, identity_file: Option<&Path>) -> Result<impl io::Read + 'a, Error> {
+    let identities: Vec<Box<dyn age::Identity>> = match identity_file {
+        Some(identity_file) => age::IdentityFile::from_file(identity_file.to_str().expect(crate::E_STR).to_string())
+            .map_err(|e| Error::io(IoOp::Read, identity_file, e))?
+            .into_identities()
+            .map_err(|e| Error::Generic(format!("Invalid identity file {}: {e}", identity_file.to_str().expect(crate::E_STR))))?,
+        None if std::env::var(ENV_BAK9_PASSPHRASE).is_ok() => vec![Box::new(age::scrypt::Identity::new(passphrase()?))],
+        None => return Err(Error::Generic(format!("An identity file (--identity) or {ENV_BAK9_PASSPHRASE} is required to decrypt {}",
+            path.to_str().expect(crate::E_STR))))
+    };
+
+    let decryptor = age::Decryptor::new(reader)
+        .map_err(|e| Error::Generic(format!("Unable to decrypt {}: {e}", path.to_str().expect(crate::E_STR))))?;
+    decryptor.decrypt(identities.iter().map(|identity| identity.as_ref() as &dyn age::Identity))
+        .map_err(|e| Error::Generic(format!("Unable to decrypt {}: {e}", path.to_str().expect(crate::E_STR))))
+}
+
+/// The user's secret hash key, which is created on first use.
+fn user_hash_key() -> Result<Vec<u8>, Error> {
+    let app_data_dir = os::user_app_data_dir(true, BAK9.into())
+        .map_err(|e| Error::Generic(e.to_string()))?;
+    let path = app_data_dir.join(HASH_KEY);
+
+    if !path.exists() {
+        let key: [u8; 32] = rand::random();
+        os::create_private_file(&path)
+            .and_then(|mut file| file.write_all(&key))
+            .map_err(|e| Error::io(IoOp::Write, &path, e))?;
+    }
+
+    fs::read(&path)
+        .map_err(|e| Error::io(IoOp::Read, &path, e))
+}
+
+/// The keyed hash of the contents of `file`, as a hex string.
+pub fn keyed_hash(file: &Path) -> Result<String, Error> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&user_hash_key()?)
+        .expect("HMAC accepts keys of any size");
+    let mut reader = fs::File::open(file)
+        .map_err(|e| Error::io(IoOp::Read, file, e))?;
+
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)
+            .map_err(|e| Error::io(IoOp::Read, file, e))?;
+        if n == 0 {
+            break
+        }
+
+        mac.update(&buffer[..n]);
+    }
+
+    Ok(mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// The manifest of the keyed hashes of the encrypted backups of `source_file` at `location`, which
+/// is either a directory or an archive.
+pub(crate) fn hash_path(source_file: &Path, location: &Path) -> PathBuf {
+    match mirror::archive_of(location) {
+        Some(_) => location.append_extension(HMAC),
+        None => location.join(source_file.filename_str().expect(E_FILENAME))
+            .append_extension(BAK9)
+            .append_extension(HMAC)
+    }
+}
+
+/// Every manifest that may hold the hashes of the backups of `source_file` within `dir`: that of its
+/// `.bak` files, followed by those of its archives.
+pub(crate) fn hash_paths(source_file: &Path, dir: &Path) -> Vec<PathBuf> {
+    std::iter::once(hash_path(source_file, dir))
+        .chain([Archive::Tar, Archive::TarGz].into_iter()
+            .map(|archive| hash_path(source_file, &dir.join(format!("{}.{BAK9}.{}",
+                source_file.filename_str().expect(E_FILENAME), archive.extension())))))
+        .collect()
+}
+
+/// The manifest of the hash of the backup at `bak_filepath`, within its directory or archive.
+fn bak_hash_path(source_file: &Path, bak_filepath: &Path) -> PathBuf {
+    hash_path(source_file, bak_filepath.parent().expect("Expected parent directory"))
+}
+
+/// Whether the backup of `source_file` at `bak_filepath` was encrypted, which is recorded by its
+/// keyed hash.
+pub fn is_encrypted(source_file: &Path, bak_filepath: &Path) -> Result<bool, Error> {
+    Ok(Manifest::load(&bak_hash_path(source_file, bak_filepath))?
+        .get(bak_filepath)
+        .is_some())
+}
+
+/// Records the keyed hash of `source_file` as that of its backup at `bak_filepath` if it was
+/// encrypted, since its contents can't be compared with later. Otherwise, forgets any hash of a
+/// previous backup of the same name.
+pub fn update_hash(source_file: &Path, bak_filepath: &Path, encrypted: bool) -> Result<(), Error> {
+    let path = bak_hash_path(source_file, bak_filepath);
+    let mut hashes = Manifest::load(&path)?;
+
+    match encrypted {
+        true => hashes.set(bak_filepath, &keyed_hash(source_file)?),
+        false => hashes.unset(bak_filepath)
+    }
+
+    hashes.save(&path)
+}
+
+/// Forgets the keyed hashes of the backups of `source_file` at `location`.
+pub fn remove_hash(source_file: &Path, location: &Path) -> Result<(), Error> {
+    manifest::remove(&hash_path(source_file, location))
+}
+
+/// Whether `source_file` matches the keyed hash of its encrypted backup at `bak_filepath`.
+pub fn matches_hash(source_file: &Path, bak_filepath: &Path) -> Result<bool, Error> {
+    match Manifest::load(&bak_hash_path(source_file, bak_filepath))?.get(bak_filepath) {
+        Some(hash) => Ok(hash == keyed_hash(source_file)?),
+        None => Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let dir = crate::test_dir("test_crypt_encrypt");
+
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.join("key.txt");
+        fs::write(&identity_file, age::secrecy::ExposeSecret::expose_secret(&identity.to_string())).unwrap();
+
+        let encryption: Encryption = identity.to_public().to_string().parse().unwrap();
+        assert!("age1invalid".parse::<Encryption>().is_err());
+        assert_eq!(Ok(Encryption::Passphrase), PASSPHRASE.parse());
+
+        let source = dir.join("source.txt");
+        let bak = dir.join("source.txt.bak");
+        fs::write(&source, "SECRET").unwrap();
+        encryption.encrypt_file(&source, &bak).unwrap();
+
+        // recognized by the record of its hash, rather than its contents
+        assert!(!is_encrypted(&source, &bak).unwrap());
+        let mut hashes = Manifest::default();
+        hashes.set(&bak, "0123");
+        hashes.save(&hash_path(&source, &dir)).unwrap();
+        assert!(is_encrypted(&source, &bak).unwrap());
+        assert!(!is_encrypted(&source, &dir.join("source.txt.bak.1")).unwrap());
+        assert_eq!(dir.join("source.txt.bak9.tar.hmac"), hash_path(&source, &dir.join("source.txt.bak9.tar")));
+        assert!(!fs::read_to_string(&bak).unwrap_or_default().contains("SECRET"));
+
+        let decrypted = decrypt_file(&bak, Some(&identity_file)).unwrap();
+        let decrypted_path = decrypted.path().to_path_buf();
+        assert_eq!("SECRET", fs::read_to_string(&decrypted_path).unwrap());
+        assert_ne!(decrypted_path, decrypt_file(&bak, Some(&identity_file)).unwrap().path());
+        drop(decrypted);
+        assert!(!decrypted_path.parent().unwrap().exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -18,26 +18,39 @@
 //! # Options
 //! 
 //! - `-d`  
-//! Deletes all backup files for the source FILE.
+//!   Deletes all backup files for the source FILE.
 //! 
 //! - `-n NUM`  
-//! Creates at most **NUM** backup files.  
-//! If not specified, defaults to 10 (0-9).
+//!   Creates at most **NUM** backup files.  
+//!   If not specified, defaults to 10 (0-9).
 
+pub mod catalog;
 pub mod cli;
+pub mod config;
+pub mod crypt;
+pub mod lock;
+pub mod manifest;
+pub mod mirror;
+pub mod notes;
 pub mod os;
+pub mod policy;
+pub mod progress;
+pub mod store;
+pub mod trash;
 
-use std::{fs, io::Write, path::{Path, PathBuf}};
-use clap::Parser;
-use file_diff;
-use thiserror;
+use std::{fs, io::{BufRead, Write}, path::{Path, PathBuf}, time::SystemTime};
+use clap::{CommandFactory, FromArgMatches};
 use colored::Colorize;
+use store::{BackupStore, FallbackStore, MirrorStore};
 
 pub const BAK9: &str = "bak9";
 const BAK: &str = "bak";
 const BAK_DOT: &str = "bak.";
 const BAK_0: &str = "bak.0";
 const BAK_1: &str = "bak.1";
+const TMP: &str = "tmp";
+/// The directory within the user's app data directory that holds the backups of stdin.
+const STDIN_DIR: &str = "@stdin";
 
 const E_STR: &str = "Expected string";
 const E_FILENAME: &str = "Expected filename";
@@ -46,33 +59,20 @@ const E_FILENAME: &str = "Expected filename";
 trait PathExt {
     fn append_extension(self, ext: &str) -> PathBuf;
     fn filename_string(self) -> Option<String>;
-    fn filename_str<'s>(&'s self) -> Option<&'s str>;
+    fn filename_str(&self) -> Option<&str>;
 }
 
 impl PathExt for PathBuf {
     fn append_extension(self, ext: &str) -> PathBuf {
-        if let Some(prev_ext) = self.extension() {
-            self.with_extension(format!("{prev_ext}.{ext}", prev_ext = prev_ext.to_str().expect(E_STR)))
-        } else {
-            self.with_extension(ext)
-        }
+        self.as_path().append_extension(ext)
     }
 
     fn filename_string(self) -> Option<String> {
-        match self.file_name() {
-            Some(filename) => match filename.to_str() {
-                Some(filename) => Some(filename.to_owned()),
-                None => None
-            },
-            None => None
-        }
+        self.as_path().filename_string()
     }
 
-    fn filename_str<'s>(&'s self) -> Option<&'s str> {
-        match self.file_name() {
-            Some(filename) => filename.to_str(),
-            None => None
-        }
+    fn filename_str(&self) -> Option<&str> {
+        self.file_name()?.to_str()
     }
 }
 
@@ -86,20 +86,11 @@ impl PathExt for &Path {
     }
 
     fn filename_string(self) -> Option<String> {
-        match self.file_name() {
-            Some(filename) => match filename.to_str() {
-                Some(filename) => Some(filename.to_owned()),
-                None => None
-            },
-            None => None
-        }
+        self.filename_str().map(|filename| filename.to_owned())
     }
 
-    fn filename_str<'s>(&'s self) -> Option<&'s str> {
-        match self.file_name() {
-            Some(filename) => filename.to_str(),
-            None => None
-        }
+    fn filename_str(&self) -> Option<&str> {
+        self.file_name()?.to_str()
     }
 }
 
@@ -116,10 +107,16 @@ pub enum IoOp {
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Unable to {op} {path}: {cause}")]
-    IO { op: IoOp, path: String, cause: String },
+    IO { op: IoOp, path: String, cause: String, kind: std::io::ErrorKind },
 
     #[error("Unable to copy {src} to {dest}: {cause}")]
-    Copy { src: String, dest: String, cause: String },
+    Copy { src: String, dest: String, cause: String, kind: std::io::ErrorKind },
+
+    #[error("Unable to access catalog {path}: {cause}")]
+    Catalog { path: String, cause: String },
+
+    #[error("Invalid config {path}: {cause}")]
+    Config { path: String, cause: String },
 
     #[error("Invalid index for {src}: {index}")]
     Index { src: String, index: u8 },
@@ -130,7 +127,7 @@ pub enum Error {
 
 impl Error {
     pub fn io(op: IoOp, path: &Path, cause: std::io::Error) -> Self {
-        Self::IO { op, path: path.to_str().expect(E_STR).cyan().to_string(), cause: cause.to_string() }
+        Self::IO { op, path: path.to_str().expect(E_STR).cyan().to_string(), cause: cause.to_string(), kind: cause.kind() }
     }
 
     pub fn index(source: &Path, index: u8) -> Self {
@@ -141,13 +138,28 @@ impl Error {
         Self::Copy {
             src: source.to_str().expect(E_STR).cyan().to_string(),
             dest: destination.to_str().expect(E_STR).cyan().to_string(),
-            cause: cause.to_string() }
+            cause: cause.to_string(),
+            kind: cause.kind() }
+    }
+
+    pub fn catalog(path: &Path, cause: rusqlite::Error) -> Self {
+        Self::Catalog { path: path.to_str().expect(E_STR).cyan().to_string(), cause: cause.to_string() }
+    }
+
+    pub fn config(path: &Path, cause: String) -> Self {
+        Self::Config { path: path.to_str().expect(E_STR).cyan().to_string(), cause }
+    }
+
+    /// Whether a copy or file operation failed due to a lack of permissions.
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self, Self::Copy { kind: std::io::ErrorKind::PermissionDenied, .. }
+            | Self::IO { kind: std::io::ErrorKind::PermissionDenied, .. })
     }
 }
 
 /// Entry point
 pub fn run() -> std::process::ExitCode {
-    match run_with(cli::Cli::parse()) {
+    match parse_cli().and_then(run_with) {
         Ok(_) => std::process::ExitCode::SUCCESS,
         Err(err) => {
             eprintln!("{} {err}", "error:".red());
@@ -156,16 +168,202 @@ pub fn run() -> std::process::ExitCode {
     }
 }
 
-pub fn run_with(cli: cli::Cli) -> Result<(), Error> {
+/// Parses the command line, filling in any options that were not given from the user's config.
+fn parse_cli() -> Result<cli::Cli, Error> {
+    let matches = cli::Cli::command().get_matches_from(cli::exec_args(std::env::args_os()));
+    let mut cli = cli::Cli::from_arg_matches(&matches)
+        .unwrap_or_else(|e| e.exit());
+
+    let mut config = config::Config::user()?;
+    let followed = cli.follow_symlinks()?;
+
+    // per-directory policies take precedence over the user's config
+    if let Some(file) = cli.file.as_ref().filter(|_| !cli.is_stdin()) {
+        config = policy::Policy::resolve(file)?.config().or(config);
+    }
+
+    config.apply(&mut cli, &matches)?;
+
+    if !cli.quiet {
+        for (link, target) in followed {
+            eprintln!("{} {} is a symlink, following it to {}", "notice:".yellow(),
+                sanitize_path_str(link.to_str().expect(E_STR)).cyan(),
+                sanitize_path_str(target.to_str().expect(E_STR)).cyan());
+        }
+    }
+
+    Ok(cli)
+}
+
+pub fn run_with(mut cli: cli::Cli) -> Result<(), Error> {
+    progress::enable(!cli.quiet);
+
+    if cli.name.is_some() && !cli.is_stdin() {
+        return Err(Error::Generic(format!("--name requires FILE to be '{}'", cli::STDIN)))
+    }
+
+    // pruned members are removed from within the archive, so there is no file to trash
+    if cli.trash && cli.archive.is_some() {
+        return Err(Error::Generic("--trash can't be used with archives".to_string()))
+    }
+
+    let (_lock, staged_file) = match cli.is_stdin() {
+        true => stage_stdin(&mut cli).map(|(lock, staged_file)| (Some(lock), staged_file))?,
+        false => (None, None)
+    };
+
+    let result = run_command(cli);
+
+    if let Some(staged_file) = staged_file {
+        let _ = fs::remove_file(staged_file);
+    }
+
+    result
+}
+
+fn run_command(cli: cli::Cli) -> Result<(), Error> {
+    // these run or watch something else, so there are no steps that could be printed instead
+    let unplanned = match cli.subcommand {
+        None if cli.watch => Some("--watch"),
+        Some(cli::Command::Watch) => Some("watch"),
+        Some(cli::Command::Edit) => Some("edit"),
+        Some(cli::Command::Exec { .. }) => Some("exec"),
+        _ => None
+    };
+
+    if let (true, Some(command)) = (cli.dry_run, unplanned) {
+        return Err(Error::Generic(format!("--dry-run can't be used with {command}")))
+    }
+
+    // backups are never deleted permanently unless asked to
+    if !trash::is_available() {
+        if cli.trash {
+            return Err(Error::Generic("No trash is available on this system to move pruned backups into".to_string()))
+        } else if matches!(cli.subcommand, Some(cli::Command::Wipe)) && !cli.no_trash {
+            return Err(Error::Generic("No trash is available on this system, use --no-trash to delete backups permanently".to_string()))
+        }
+    }
+
     match cli.subcommand {
+        None if cli.watch => run_watch(&cli),
+        None if cli.dry_run => run_backup_dry_run(&cli),
         None => run_backup(&cli),
-        Some(cli::Command::List) => run_list(&cli),
+        Some(cli::Command::Watch) => run_watch(&cli),
+        Some(cli::Command::Edit) => run_edit(&cli),
+        Some(cli::Command::Exec { ref files, restore_on_failure, ref command }) => run_exec(&cli, files, restore_on_failure, command),
+        Some(cli::Command::List { all: true }) => run_list_all(),
+        Some(cli::Command::List { all: false }) => run_list(&cli),
         Some(cli::Command::Wipe) => run_wipe(&cli),
-        Some(cli::Command::Diff { index }) => run_diff(&cli, index)
+        Some(cli::Command::Diff { ref index }) => run_diff(&cli, index),
+        Some(cli::Command::Restore { ref index }) => run_restore(&cli, index),
+        Some(cli::Command::Show { ref index }) => run_show(&cli, index),
+        Some(cli::Command::Grep { ref pattern, first_seen, last_seen }) => run_grep(&cli, pattern, first_seen, last_seen),
+        Some(cli::Command::Log { patch }) => run_log(&cli, patch),
+        Some(cli::Command::Verify { all }) => run_verify(&cli, all),
+        Some(cli::Command::Gc) => run_gc(&cli),
+        Some(cli::Command::Move { ref old, ref new }) => run_move(&cli, old, new),
+        Some(cli::Command::Catalog { ref prefix, ref since, ref until }) => run_catalog(catalog::Filter {
+            prefix: prefix.clone(),
+            since: since.clone(),
+            until: until.clone()
+        })
     }
 }
 
-fn remove_app_data_subdir(app_data_dir: &Path, app_data_subdir: &Path) -> Result<(), Error> {
+fn user_app_data_dir() -> Result<PathBuf, Error> {
+    os::user_app_data_dir(true, BAK9.into())
+        .map_err(|e| Error::Generic(e.to_string()))
+}
+
+/// Points FILE at a file named NAME within the stdin directory, which is also made DIR. For the
+/// commands that compare with stdin, it's read into that file, which is returned so that it can be
+/// deleted afterwards. The backups of NAME are locked first, and stay locked until the returned
+/// lock is dropped, so that simultaneous runs with the same NAME never overwrite each other's file.
+fn stage_stdin(cli: &mut cli::Cli) -> Result<(lock::Lock, Option<PathBuf>), Error> {
+    let name = cli.name.as_deref()
+        .ok_or_else(|| Error::Generic("--name is required to back up stdin".to_string()))?;
+
+    let is_staged = match cli.subcommand {
+        None if cli.watch => return Err(Error::Generic("stdin can't be watched".to_string())),
+        None | Some(cli::Command::Diff { .. }) => true,
+        Some(cli::Command::List { all: false }
+            | cli::Command::Restore { .. }
+            | cli::Command::Show { .. }
+            | cli::Command::Grep { .. }
+            | cli::Command::Log { .. }
+            | cli::Command::Wipe) => false,
+        Some(_) => return Err(Error::Generic("This command can't be used with stdin".to_string()))
+    };
+
+    let app_data_dir = user_app_data_dir()?;
+    let stdin_dir = app_data_dir.join(STDIN_DIR);
+    os::create_private_dir_all(&stdin_dir)
+        .map_err(|e| Error::io(IoOp::Create, &stdin_dir, e))?;
+
+    let file = stdin_dir.join(name);
+    let lock = lock_backups(cli, &file, &stdin_dir, &app_data_dir)?;
+    cli.file = Some(file.clone());
+    cli.dir = Some(stdin_dir);
+
+    if !is_staged {
+        // left behind by an interrupted run
+        if file.exists() {
+            fs::remove_file(&file)
+                .map_err(|e| Error::io(IoOp::Delete, &file, e))?;
+        }
+
+        return Ok((lock, None))
+    }
+
+    let mut writer = os::create_private_file(&file)
+        .map_err(|e| Error::io(IoOp::Create, &file, e))?;
+    if let Err(e) = std::io::copy(&mut std::io::stdin().lock(), &mut writer) {
+        let _ = fs::remove_file(&file);
+        return Err(Error::io(IoOp::Write, &file, e))
+    }
+
+    Ok((lock, Some(file)))
+}
+
+/// The store that holds the backups of FILE in DIR, along with any that fell back to the app data
+/// mirror because DIR couldn't be written to. This is the same store that backups are put in.
+fn dir_store(cli: &cli::Cli) -> Result<Box<dyn BackupStore>, Error> {
+    let file = cli.file()?;
+    let dir = cli.dir();
+    let app_data_dir = user_app_data_dir()?;
+    let mirror_store = backup_mirror_store(cli, file, &app_data_dir);
+
+    if dir == app_data_dir {
+        Ok(Box::new(mirror_store))
+    } else {
+        Ok(Box::new(FallbackStore::new(backup_dir_store(cli, file, &dir), Box::new(mirror_store))))
+    }
+}
+
+/// All stores that may hold backups of FILE, for commands that operate on every location.
+/// The app data mirror is always included when it is DIR, otherwise only if `include_mirror` is set.
+fn all_stores(cli: &cli::Cli, include_mirror: bool) -> Result<Vec<Box<dyn BackupStore>>, Error> {
+    file_stores(cli, cli.file()?, include_mirror)
+}
+
+/// All stores that may hold backups of `file`, in DIR or beside it. See [all_stores].
+fn file_stores(cli: &cli::Cli, file: &Path, include_mirror: bool) -> Result<Vec<Box<dyn BackupStore>>, Error> {
+    let dir = cli.dir_for(Some(file));
+    let app_data_dir = user_app_data_dir()?;
+    let mut stores: Vec<Box<dyn BackupStore>> = Vec::new();
+
+    if dir != app_data_dir {
+        stores.push(store::dir_store(file, &dir, cli.archive));
+    }
+
+    if dir == app_data_dir || include_mirror {
+        stores.push(Box::new(MirrorStore::new(file, &app_data_dir).with_archive(cli.archive)));
+    }
+
+    Ok(stores)
+}
+
+pub(crate) fn remove_app_data_subdir(app_data_dir: &Path, app_data_subdir: &Path) -> Result<(), Error> {
     let mut subdir = app_data_subdir.to_path_buf();
 
     while subdir.is_dir() && subdir != app_data_dir && subdir.starts_with(app_data_dir) {
@@ -185,17 +383,14 @@ fn remove_app_data_subdir(app_data_dir: &Path, app_data_subdir: &Path) -> Result
     Ok(())
 }
 
-fn confirm_wipe(source_file: &Path, dir: &Path, force: bool) -> bool {
+/// Prompts the user with a yes/no question, unless `force` is set.
+fn confirm(question: &str, force: bool) -> bool {
     if force {
         return true
     }
 
     let mut input = String::new();
-    print!("{} Delete all backups of {} in {}? {} ",
-        "confirm:".bright_yellow(), 
-        source_file.filename_str().expect(E_FILENAME).cyan(),
-        sanitize_path_str(dir.to_str().expect(E_STR)).cyan(),
-        "(y/N):".magenta());
+    print!("{} {question} {} ", "confirm:".bright_yellow(), "(y/N):".magenta());
 
     std::io::stdout().flush()
         .expect("Failed to flush stdout");
@@ -206,267 +401,1161 @@ fn confirm_wipe(source_file: &Path, dir: &Path, force: bool) -> bool {
         .to_lowercase() == "y"
 }
 
+fn confirm_wipe(source_file: &Path, dir: &Path, force: bool) -> bool {
+    confirm(&format!("Delete all backups of {} in {}?",
+        source_file.filename_str().expect(E_FILENAME).cyan(),
+        sanitize_path_str(dir.to_str().expect(E_STR)).cyan()),
+        force)
+}
+
 fn run_wipe(cli: &cli::Cli) -> Result<(), Error> {
-    let dir = cli.dir();
-    let app_data_dir = os::user_app_data_dir(true, BAK9.into())
-        .map_err(|e| Error::Generic(e.to_string()))?;
-    
-    if dir == app_data_dir {
-        match mirror_dir(&app_data_dir, &cli.file, false) {
-            Ok(mirror_dir) => {
-                if confirm_wipe(&cli.file, &mirror_dir, cli.force) {
-                    wipe(&cli.file, &mirror_dir)?;
-                    remove_app_data_subdir(&app_data_dir, &mirror_dir)?;
-                }
-            },
-            Err(_) => {}
+    // if dir was not specified (default), continue on to wipe the app data dir
+    for store in all_stores(cli, cli.dir.is_none())? {
+        if store.list()?.is_empty() {
+            continue
         }
-    } else {
-        if confirm_wipe(&cli.file, &dir, cli.force) {
-            wipe(&cli.file, &dir)?;
+
+        if cli.dry_run {
+            print_steps(&store.plan_delete()?);
+            continue
+        }
+
+        if confirm_wipe(store.source_file(), &store.location(), cli.force) {
+            if cli.no_trash {
+                store.delete()?;
+            } else {
+                store.trash()?;
+            }
+
+            notes::remove(&notes::manifest_path(store.as_ref()))?;
         }
     }
 
-    // if dir was not specified (default), continue on to wipe the app data dir
-    if cli.dir.is_some() {
+    Ok(())
+}
+
+fn run_list(cli: &cli::Cli) -> Result<(), Error> {
+    for store in all_stores(cli, true)? {
+        print_list_backups(store.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn print_list_backups(store: &dyn BackupStore) -> Result<(), Error> {
+    let bak_filepaths = store.list()?;
+
+    if bak_filepaths.is_empty() {
         return Ok(())
     }
 
-    if dir != app_data_dir {
-        match mirror_dir(&app_data_dir, &cli.file, false) {
-            Ok(mirror_dir) => {
-                if confirm_wipe(&cli.file, &mirror_dir, cli.force) {
-                    wipe(&cli.file, &mirror_dir)?;
-                    remove_app_data_subdir(&app_data_dir, &mirror_dir)?;
-                }
-            },
-            Err(_) => {}
+    println!("Backups of {file} in {dir}:",
+        file = sanitize_path_str(store.source_file().to_str().expect(E_STR)).cyan(),
+        dir = sanitize_path_str(store.location().to_str().expect(E_STR)).cyan());
+
+    let notes = notes::Notes::load(&notes::manifest_path(store))?;
+    for bak_filepath in &bak_filepaths {
+        match notes.get(bak_filepath) {
+            Some(note) => println!("    {}  {}", bak_filepath.filename_str().expect(E_STR).green(), note.italic()),
+            None => println!("    {}", bak_filepath.filename_str().expect(E_STR).green())
         }
     }
 
     Ok(())
 }
 
-fn run_list(cli: &cli::Cli) -> Result<(), Error> {
-    let dir = cli.dir();
-    let app_data_dir = os::user_app_data_dir(true, BAK9.into())
-        .map_err(|e| Error::Generic(e.to_string()))?;
+fn run_list_all() -> Result<(), Error> {
+    let app_data_dir = user_app_data_dir()?;
+    let sources = mirror::walk(&app_data_dir)?;
 
-    if dir != app_data_dir {
-        print_list_backups(&cli.file, &dir)?;
+    if sources.is_empty() {
+        return Ok(())
     }
 
-    match mirror_dir(&app_data_dir, &cli.file, false) {
-        Ok(mirror_dir) => print_list_backups(&cli.file, &mirror_dir)?,
-        Err(_) => {}
+    println!("Backups in {dir}:",
+        dir = sanitize_path_str(app_data_dir.to_str().expect(E_STR)).cyan());
+
+    for source in sources {
+        let summary = source.summary()?;
+        println!("    {file}  {count} {backups}  {size}  {newest}",
+            file = sanitize_path_str(source.source_file.to_str().expect(E_STR)).green(),
+            count = summary.count,
+            backups = if summary.count == 1 { "backup" } else { "backups" },
+            size = format_size(summary.size),
+            newest = summary.newest.map(format_time).unwrap_or_default().yellow());
     }
 
     Ok(())
 }
 
-fn print_list_backups(source_file: &Path, dir: &Path) -> Result<(), Error> {
-    if !dir.is_dir() {
+/// Deletes all mirrored backups whose source file no longer exists.
+fn run_gc(cli: &cli::Cli) -> Result<(), Error> {
+    let app_data_dir = user_app_data_dir()?;
+    let orphans: Vec<mirror::MirroredSource> = mirror::walk(&app_data_dir)?
+        .into_iter()
+        .filter(|source| source.is_orphan())
+        .collect();
+
+    if orphans.is_empty() {
         return Ok(())
     }
 
-    let mut bak_filepaths = list_bak_n_files(source_file, dir)?;
+    let mut total_count = 0;
+    let mut total_size = 0;
 
-    let bak_file = dir.join(source_file.filename_str().expect(E_FILENAME))
-        .append_extension(BAK);
+    if !cli.quiet {
+        println!("Orphaned backups in {dir}:",
+            dir = sanitize_path_str(app_data_dir.to_str().expect(E_STR)).cyan());
+    }
+
+    for source in &orphans {
+        let summary = source.summary()?;
+        total_count += summary.count;
+        total_size += summary.size;
 
-    if bak_file.exists() {
-        bak_filepaths.push(bak_file);
-        bak_filepaths.sort();
+        if !cli.quiet {
+            println!("    {file}  {count}  {size}",
+                file = sanitize_path_str(source.source_file.to_str().expect(E_STR)).green(),
+                count = summary.count,
+                size = format_size(summary.size));
+        }
     }
 
-    if bak_filepaths.is_empty() {
+    if cli.dry_run {
+        for source in &orphans {
+            for store in mirror::stores(source) {
+                print_steps(&store.plan_delete()?);
+            }
+        }
+
         return Ok(())
     }
 
-    println!("Backups of {file} in {dir}:",
-        file = sanitize_path_str(source_file.to_str().expect(E_STR)).cyan(),
-        dir = sanitize_path_str(dir.to_str().expect(E_STR)).cyan());
+    if !confirm(&format!("Delete {} orphaned backups ({})?", total_count, format_size(total_size)), cli.force) {
+        return Ok(())
+    }
+
+    for source in &orphans {
+        for store in mirror::stores(source) {
+            store.delete()?;
+        }
 
-    for bak_filepath in bak_filepaths {
-        println!("    {}", bak_filepath.filename_str().expect(E_STR).green());
+        notes::remove_all(&source.source_file, &source.mirror_dir)?;
+        remove_app_data_subdir(&app_data_dir, &source.mirror_dir)?;
     }
 
     Ok(())
 }
 
-fn run_diff(cli: &cli::Cli, index: u8) -> Result<(), Error> {
-    let source_file = &cli.file;
-    let mut dir = cli.dir();
-    let app_data_dir = os::user_app_data_dir(true, BAK9.into())
-        .map_err(|e| Error::Generic(e.to_string()))?;
+/// Warns about every backup of FILE, or of every mirrored file if `all` is set, that grants
+/// permissions its source file doesn't. Also warns about mirror directories that other users can
+/// access.
+fn run_verify(cli: &cli::Cli, all: bool) -> Result<(), Error> {
+    let app_data_dir = user_app_data_dir()?;
+    let mut stores = Vec::new();
+    let mut mirror_dirs = std::collections::BTreeSet::new();
+
+    if all {
+        for source in mirror::walk(&app_data_dir)? {
+            // an orphan has no permissions to compare with
+            if !source.is_orphan() {
+                stores.extend(mirror::stores(&source));
+                mirror_dirs.insert(source.mirror_dir);
+            }
+        }
+    } else {
+        stores = all_stores(cli, true)?;
+        mirror_dirs.extend(mirror_dir(&app_data_dir, cli.file()?, false).ok());
+    }
+
+    let path_str = |path: &Path| sanitize_path_str(path.to_str().expect(E_STR)).cyan();
+    let mut count = 0;
+    let mut warnings = 0;
 
-    if dir == app_data_dir {
-        dir = mirror_dir(&app_data_dir, source_file, false)
-            .map_err(|_| Error::index(source_file, index))?;
+    for store in &stores {
+        let source_file = store.source_file();
+
+        for bak_filepath in store.files()? {
+            count += 1;
+
+            let excess = os::excess_permissions(source_file, &bak_filepath)
+                .map_err(|e| Error::io(IoOp::Read, &bak_filepath, e))?;
+
+            if excess != 0 {
+                warnings += 1;
+                eprintln!("{} {} is more permissive than {} ({:04o} > {:04o})", "warning:".yellow(),
+                    path_str(&bak_filepath), path_str(source_file),
+                    os::permission_bits(&bak_filepath).unwrap_or(0),
+                    os::permission_bits(source_file).unwrap_or(0));
+            }
+        }
     }
 
-    let bak_file = dir.join(source_file.filename_str().expect(E_FILENAME))
-        .append_extension(BAK);
+    // every directory from the mirror up to the app data directory should be private
+    let dirs: std::collections::BTreeSet<&Path> = mirror_dirs.iter()
+        .flat_map(|mirror_dir| mirror_dir.ancestors())
+        .filter(|dir| dir.starts_with(&app_data_dir))
+        .collect();
+
+    for dir in dirs {
+        let mode = os::permission_bits(dir)
+            .map_err(|e| Error::io(IoOp::Read, dir, e))?;
 
-    if bak_file.exists() {
-        if index > 0 {
-            return Err(Error::index(source_file, index));
+        if mode & 0o077 != 0 {
+            warnings += 1;
+            eprintln!("{} {} is accessible by other users ({:04o})", "warning:".yellow(), path_str(dir), mode);
         }
+    }
 
-        os::print_diff(source_file, &bak_file)
-    } else {
-        let bak_filepaths = list_bak_n_files(source_file, &dir)?;
-        let bak_file = bak_filepaths.get(index as usize)
-            .ok_or_else(|| Error::index(source_file, index))?;
+    if warnings > 0 {
+        return Err(Error::Generic(format!("Verification failed with {warnings} {}", if warnings == 1 { "warning" } else { "warnings" })))
+    }
 
-        os::print_diff(source_file, bak_file)
+    if !cli.quiet {
+        println!("Verified {count} {}", if count == 1 { "backup" } else { "backups" });
     }
+
+    Ok(())
 }
 
-/// Performs a wipe of all `.bak` files in the directory.
-fn wipe(source_file: &Path, dest_dir: &Path) -> Result<(), Error> {
-    let bak_filepaths = list_bak_n_files(source_file, dest_dir)?;
+fn run_diff(cli: &cli::Cli, selector: &cli::Selector) -> Result<(), Error> {
+    let store = dir_store(cli)?;
+    let index = select(store.as_ref(), selector)?;
+    if listed_backup(store.as_ref(), index)?.is_symlink() {
+        print_link_diff(cli.file()?, &store.get(index)?);
+        return Ok(())
+    }
+
+    with_readable_backup(cli, store.as_ref(), index, |bak_file| os::print_diff(cli.file()?, bak_file, cli.diff_tool.as_deref()))
+}
 
-    for bak_filepath in bak_filepaths {
-        std::fs::remove_file(&bak_filepath)
-            .map_err(|e| Error::io(IoOp::Delete, &bak_filepath, e))?;
+fn run_show(cli: &cli::Cli, selector: &cli::Selector) -> Result<(), Error> {
+    let store = dir_store(cli)?;
+    let index = select(store.as_ref(), selector)?;
+    let bak_file = listed_backup(store.as_ref(), index)?;
+    if bak_file.is_symlink() {
+        let target = fs::read_link(&bak_file)
+            .map_err(|e| Error::io(IoOp::Read, &bak_file, e))?;
+        println!("{}", target.to_str().expect(E_STR));
+        return Ok(())
     }
 
-    // wipe the .bak if it exists as well
-    let bak_filepath = dest_dir
-        .join(source_file.filename_str().expect(E_FILENAME))
-        .append_extension(BAK);
+    print_backup(cli, store.as_ref(), index)
+}
+
+fn run_grep(cli: &cli::Cli, pattern: &regex::bytes::Regex, first_seen: bool, last_seen: bool) -> Result<(), Error> {
+    for store in all_stores(cli, true)? {
+        let versions = versions(store.as_ref())?;
+        if versions.is_empty() {
+            continue
+        }
+
+        let heading = format!("Backups of {file} in {dir}:",
+            file = sanitize_path_str(store.source_file().to_str().expect(E_STR)).cyan(),
+            dir = sanitize_path_str(store.location().to_str().expect(E_STR)).cyan());
+        let matches = |position: usize| grep_backup(cli, store.as_ref(), &versions[position], pattern)
+            .map(|lines| !lines.is_empty());
+
+        if first_seen {
+            // the newest backups match, up to the one where PATTERN appeared
+            let absent = bisect(versions.len(), matches)?;
+            println!("{heading}");
+
+            match absent.checked_sub(1) {
+                Some(seen) => {
+                    println!("    first seen in {}", describe_version(&versions[seen]));
+                    if let Some(version) = versions.get(absent) {
+                        println!("    absent from   {}", describe_version(version));
+                    }
+                },
+                None => println!("    absent from the latest, {}", describe_version(&versions[0]))
+            }
+        } else if last_seen {
+            // the newest backups don't match, down to the last one that PATTERN was in
+            let seen = bisect(versions.len(), |position| matches(position).map(|matches| !matches))?;
+            println!("{heading}");
+
+            match (versions.get(seen), seen.checked_sub(1)) {
+                (Some(version), Some(vanished)) => {
+                    println!("    last seen in  {}", describe_version(version));
+                    println!("    vanished from {}", describe_version(&versions[vanished]));
+                },
+                (Some(version), None) => println!("    still in the latest, {}", describe_version(version)),
+                (None, _) => println!("    absent from the oldest, {}", describe_version(&versions[versions.len() - 1]))
+            }
+        } else {
+            let mut printed_heading = false;
+
+            for version in &versions {
+                for (line_number, line) in grep_backup(cli, store.as_ref(), version, pattern)? {
+                    if !printed_heading {
+                        println!("{heading}");
+                        printed_heading = true;
+                    }
+
+                    println!("    {}  {}: {line}", describe_version(version), line_number.to_string().cyan());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_log(cli: &cli::Cli, patch: bool) -> Result<(), Error> {
+    let stores = all_stores(cli, true)?;
+    let mut versions_by_location = Vec::new();
+    for store in &stores {
+        let location = store.location();
+        versions_by_location.extend(versions(store.as_ref())?
+            .into_iter()
+            .map(|version| (location.clone(), store.as_ref(), version)));
+    }
+
+    if versions_by_location.is_empty() {
+        return Ok(())
+    }
+
+    // merges the locations, which are each already in order
+    versions_by_location.sort_by_key(|(_, _, version)| std::cmp::Reverse(version.time));
+    let merged = versions_by_location.iter().any(|(location, _, _)| *location != versions_by_location[0].0);
+
+    println!("History of {file}:", file = sanitize_path_str(cli.file()?.to_str().expect(E_STR)).cyan());
+
+    let (_, store, version) = &versions_by_location[0];
+    let mut newer = read_content(cli, *store, version)?;
+    for (position, (location, _, version)) in versions_by_location.iter().enumerate() {
+        // the oldest backup is compared with nothing, so that all of its lines are added
+        let older = match versions_by_location.get(position + 1) {
+            Some((_, older_store, older_version)) => read_content(cli, *older_store, older_version)?,
+            None => Content::Text(String::new())
+        };
+
+        // a text version is compared with nothing if the older one is a symlink or binary
+        let change = match &newer {
+            Content::Text(newer) => {
+                let (insertions, deletions) = diffstat(older.text().unwrap_or_default(), newer);
+                format!("{} {}", format!("+{insertions}").green(), format!("-{deletions}").red())
+            },
+            Content::Link(target) => format!("-> {}", target.to_str().expect(E_STR)),
+            Content::Binary => "binary".to_string()
+        };
+        let location = match merged {
+            true => format!("  {}", sanitize_path_str(location.to_str().expect(E_STR)).cyan()),
+            false => String::new()
+        };
+
+        let note = version.note.as_deref()
+            .map(|note| format!("  {}", note.italic()))
+            .unwrap_or_default();
+
+        println!("    {}  {}  {change}{location}{note}", describe_version(version), format_size(version.size));
+
+        if let (true, Content::Text(newer)) = (patch, &newer) {
+            print_patch(older.text().unwrap_or_default(), newer);
+        }
 
-    if bak_filepath.exists() {
-        std::fs::remove_file(&bak_filepath)
-            .map_err(|e| Error::io(IoOp::Delete, &bak_filepath, e))?;
+        newer = older;
     }
 
     Ok(())
 }
 
-/// Retrieves a list of all `.bak.N` files in the directory.
-fn list_bak_n_files(file: &Path, dir: &Path) -> Result<Vec<PathBuf>, Error> {
-    let bak_n_file_pattern = file
-        .append_extension(BAK_DOT)
-        .filename_string().expect(E_FILENAME);
+/// The contents of a version, as far as they can be compared by line.
+enum Content {
+    Text(String),
+    /// A backup of a symlink, with its target
+    Link(PathBuf),
+    /// Binary, or too large to compare by line
+    Binary
+}
+
+impl Content {
+    fn text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            _ => None
+        }
+    }
+}
+
+/// Reads the contents of `version` in `store`, streaming the backup rather than retrieving it.
+fn read_content(cli: &cli::Cli, store: &dyn BackupStore, version: &Version) -> Result<Content, Error> {
+    if version.bak_filepath.is_symlink() {
+        return fs::read_link(&version.bak_filepath)
+            .map(Content::Link)
+            .map_err(|e| Error::io(IoOp::Read, &version.bak_filepath, e))
+    }
 
-    let dir = dir.read_dir()
-        .map_err(|e| Error::io(IoOp::Read, dir, e))?;
-    let mut paths: Vec<PathBuf> = dir
-        .filter_map(|entry| match entry {
-            Ok(entry) => Some(entry.path()),
-            Err(_) => None
+    if version.size >= progress::THRESHOLD {
+        return Ok(Content::Binary)
+    }
+
+    let mut contents = Vec::new();
+    read_backup(cli, store, version.index, &mut |reader| reader.read_to_end(&mut contents)
+        .map(|_| ())
+        .map_err(|e| Error::io(IoOp::Read, &version.bak_filepath, e)))?;
+
+    Ok(match contents.contains(&0) {
+        true => Content::Binary,
+        false => Content::Text(String::from_utf8_lossy(&contents).into_owned())
+    })
+}
+
+/// The number of lines inserted and deleted between `older` and `newer`.
+fn diffstat(older: &str, newer: &str) -> (usize, usize) {
+    similar::TextDiff::from_lines(older, newer)
+        .iter_all_changes()
+        .fold((0, 0), |(insertions, deletions), change| match change.tag() {
+            similar::ChangeTag::Insert => (insertions + 1, deletions),
+            similar::ChangeTag::Delete => (insertions, deletions + 1),
+            similar::ChangeTag::Equal => (insertions, deletions)
         })
-        .filter(|path| path.is_file())
-        .filter(|filepath| {
-            let filename = match filepath.file_name() {
-                Some(filename) => filename.to_str().expect(E_STR),
-                None => return false
-            };
+}
 
-            if filename.starts_with(&bak_n_file_pattern) {
-                matches!(filename.trim_start_matches(&bak_n_file_pattern).parse::<u32>(), Ok(_))
-            } else {
-                false
+/// Prints the hunks that change `older` into `newer`, indented beneath their version.
+fn print_patch(older: &str, newer: &str) {
+    let diff = similar::TextDiff::from_lines(older, newer);
+
+    for hunk in diff.unified_diff().iter_hunks() {
+        println!("        {}", hunk.header().to_string().cyan());
+
+        for change in hunk.iter_changes() {
+            let line = change.to_string_lossy();
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            match change.tag() {
+                similar::ChangeTag::Insert => println!("        {}", format!("+{line}").green()),
+                similar::ChangeTag::Delete => println!("        {}", format!("-{line}").red()),
+                similar::ChangeTag::Equal => println!("         {line}")
             }
+        }
+    }
+}
+
+/// A backup of FILE, when it was taken, and the note attached to it. Its path is as listed by its
+/// store, which for an archived backup is a path within the archive.
+struct Version {
+    index: u8,
+    bak_filepath: PathBuf,
+    time: Option<SystemTime>,
+    size: u64,
+    note: Option<String>
+}
+
+/// Every backup in `store`, most recent first, without retrieving them.
+fn versions(store: &dyn BackupStore) -> Result<Vec<Version>, Error> {
+    let notes = notes::Notes::load(&notes::manifest_path(store))?;
+
+    store.list()?.into_iter()
+        .zip(store.timestamps()?)
+        .zip(store.sizes()?)
+        .enumerate()
+        .map(|(index, ((bak_filepath, time), size))| {
+            let index = u8::try_from(index).map_err(|_| Error::index(store.source_file(), u8::MAX))?;
+            let note = notes.get(&bak_filepath).map(str::to_string);
+
+            Ok(Version { index, bak_filepath, time, size, note })
         })
-        .collect();
+        .collect()
+}
+
+/// Describes a version as its `bak.N` index and timestamp.
+fn describe_version(version: &Version) -> String {
+    format!("{}  {}",
+        format!("bak.{}", version.index).green(),
+        version.time.map(format_time).unwrap_or_default().yellow())
+}
+
+/// The lines of `version` in `store` that match `pattern`, along with their line numbers. The
+/// backup is streamed rather than retrieved, and backups of symlinks have no lines.
+fn grep_backup(cli: &cli::Cli, store: &dyn BackupStore, version: &Version, pattern: &regex::bytes::Regex) -> Result<Vec<(usize, String)>, Error> {
+    if version.bak_filepath.is_symlink() {
+        return Ok(Vec::new())
+    }
+
+    let mut lines = Vec::new();
+    read_backup(cli, store, version.index, &mut |reader| {
+        for (n, line) in reader.split(b'\n').enumerate() {
+            let line = line.map_err(|e| Error::io(IoOp::Read, &version.bak_filepath, e))?;
+            if pattern.is_match(&line) {
+                lines.push((n + 1, String::from_utf8_lossy(&line).trim_end_matches('\r').to_string()));
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(lines)
+}
+
+/// The position of the first of `len` items for which `test` fails, given that it holds for every
+/// item before that position and for none after it.
+fn bisect(len: usize, mut test: impl FnMut(usize) -> Result<bool, Error>) -> Result<usize, Error> {
+    let (mut low, mut high) = (0, len);
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if test(mid)? {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+
+    Ok(low)
+}
+
+/// Shows the differences between the targets of a symlinked `file` and a backup of the link.
+fn print_link_diff(file: &Path, bak_filepath: &Path) {
+    let describe = |path: &Path| match fs::read_link(path) {
+        Ok(target) => format!("{} -> {}", path.filename_str().expect(E_FILENAME), target.to_str().expect(E_STR)),
+        Err(_) => format!("{} is not a symlink", path.filename_str().expect(E_FILENAME))
+    };
+
+    if file.is_symlink() && fs::read_link(file).ok() == fs::read_link(bak_filepath).ok() {
+        println!("No difference");
+    } else {
+        println!("{}", format!("- {}", describe(bak_filepath)).red());
+        println!("{}", format!("+ {}", describe(file)).green());
+    }
+}
+
+/// Writes the contents of the backup at `index` in `store` to stdout as it is read, decrypting it if
+/// necessary.
+fn print_backup(cli: &cli::Cli, store: &dyn BackupStore, index: u8) -> Result<(), Error> {
+    let bak_filepath = listed_backup(store, index)?;
+
+    read_backup(cli, store, index, &mut |reader| {
+        let mut stdout = std::io::stdout().lock();
+        std::io::copy(reader, &mut stdout)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| Error::io(IoOp::Read, &bak_filepath, e))
+    })
+}
+
+/// Streams the backup at `index` in `store` to `read`, decrypting it as it is read if necessary.
+fn read_backup(cli: &cli::Cli, store: &dyn BackupStore, index: u8, read: &mut dyn FnMut(&mut dyn BufRead) -> Result<(), Error>) -> Result<(), Error> {
+    let bak_filepath = listed_backup(store, index)?;
+    let is_encrypted = crypt::is_encrypted(store.source_file(), &bak_filepath)?;
+
+    store.read(index, &mut |reader| {
+        let mut reader = std::io::BufReader::new(reader);
+        if is_encrypted {
+            read(&mut std::io::BufReader::new(crypt::decrypt(reader, &bak_filepath, cli.identity.as_deref())?))
+        } else {
+            read(&mut reader)
+        }
+    })
+}
+
+/// The path of the backup at `index` as listed by `store`, without retrieving it. An archived
+/// backup is listed as a path within its archive.
+fn listed_backup(store: &dyn BackupStore, index: u8) -> Result<PathBuf, Error> {
+    store.list()?
+        .into_iter()
+        .nth(usize::from(index))
+        .ok_or_else(|| Error::index(store.source_file(), index))
+}
+
+/// Whether the backup at `index` in `store` is encrypted, as recorded by its keyed hash.
+fn is_encrypted_backup(store: &dyn BackupStore, index: u8) -> Result<bool, Error> {
+    crypt::is_encrypted(store.source_file(), &listed_backup(store, index)?)
+}
+
+/// Calls `f` with a readable copy of the backup at `index` in `store`. Encrypted backups are
+/// decrypted into a private temporary file with the identity, which is deleted afterwards.
+fn with_readable_backup<T>(cli: &cli::Cli, store: &dyn BackupStore, index: u8, f: impl FnOnce(&Path) -> Result<T, Error>) -> Result<T, Error> {
+    let bak_filepath = store.get(index)?;
+    if !is_encrypted_backup(store, index)? {
+        return f(&bak_filepath)
+    }
+
+    let decrypted = crypt::decrypt_file(&bak_filepath, cli.identity.as_deref())?;
+    f(decrypted.path())
+}
+
+/// The file that restoring a backup to `dest` overwrites.
+fn restore_dest(cli: &cli::Cli, dest: &Path) -> Result<PathBuf, Error> {
+    // backups kept beside a link are of the file it points to, which is restored in its place
+    if cli.beside_link && dest.is_symlink() {
+        dest.canonicalize()
+            .map_err(|e| Error::io(IoOp::Read, dest, e))
+    } else {
+        Ok(dest.to_path_buf())
+    }
+}
+
+/// Overwrites `dest` with the backup at `index`, decrypting it if necessary.
+fn restore_backup(cli: &cli::Cli, store: &dyn BackupStore, index: u8, dest: &Path) -> Result<(), Error> {
+    let dest = &restore_dest(cli, dest)?;
+    if listed_backup(store, index)?.is_symlink() || !is_encrypted_backup(store, index)? {
+        return store.restore(index, dest)
+    }
+
+    let bak_filepath = store.get(index)?;
+    let permissions = bak_filepath.metadata()
+        .map_err(|e| Error::io(IoOp::Read, &bak_filepath, e))?
+        .permissions();
+
+    let decrypted = crypt::decrypt_file(&bak_filepath, cli.identity.as_deref())?;
+    os::copy_file(decrypted.path(), dest)
+        .map_err(|e| Error::copy(&bak_filepath, dest, e))?;
+
+    // the decrypted copy is private to the user, rather than having the permissions of the backup
+    fs::set_permissions(dest, permissions)
+        .map_err(|e| Error::io(IoOp::Write, dest, e))
+}
+
+fn confirm_restore(source_file: &Path, index: u8, force: bool) -> bool {
+    confirm(&format!("Overwrite {} with backup {}?",
+        sanitize_path_str(source_file.to_str().expect(E_STR)).cyan(),
+        index.to_string().cyan()),
+        force)
+}
+
+fn run_restore(cli: &cli::Cli, selector: &cli::Selector) -> Result<(), Error> {
+    let file = cli.file()?;
+    let store = dir_store(cli)?;
+    let index = select(store.as_ref(), selector)?;
+
+    // stdin's backups have no file to overwrite
+    if cli.name.is_some() {
+        return print_backup(cli, store.as_ref(), index)
+    }
+
+    // make sure that the backup exists before asking
+    let bak_filepath = listed_backup(store.as_ref(), index)?;
+
+    if cli.dry_run {
+        print_steps(&[store::Step::Copy(bak_filepath, restore_dest(cli, file)?)]);
+        return Ok(())
+    }
+
+    if confirm_restore(file, index, cli.force) {
+        restore_backup(cli, store.as_ref(), index, file)?;
+    }
+
+    Ok(())
+}
+
+/// The index of the backup in `store` that `selector` selects: the newest one with its note, if
+/// it selects one by note.
+fn select(store: &dyn BackupStore, selector: &cli::Selector) -> Result<u8, Error> {
+    let note = match selector {
+        cli::Selector::Index(index) => return Ok(*index),
+        cli::Selector::Note(note) => note
+    };
 
-    paths.sort();
+    let notes = notes::Notes::load(&notes::manifest_path(store))?;
+    if let Some(index) = store.list()?.iter().position(|bak_filepath| notes.get(bak_filepath) == Some(note.as_str())) {
+        return u8::try_from(index).map_err(|_| Error::index(store.source_file(), u8::MAX))
+    }
 
-    Ok(paths)
+    Err(Error::Generic(format!("No backup of {} in {} has the note {selector}",
+        sanitize_path_str(store.source_file().to_str().expect(E_STR)),
+        sanitize_path_str(store.location().to_str().expect(E_STR)))))
+}
+
+/// Attaches `note` to the newest backup in `store`, forgetting the notes on any pruned backups.
+fn attach_note(store: &dyn BackupStore, note: &str) -> Result<(), Error> {
+    let path = notes::manifest_path(store);
+    let bak_filepaths = store.list()?;
+
+    let mut notes = notes::Notes::load(&path)?;
+    notes.retain(&bak_filepaths);
+    if let Some(bak_filepath) = bak_filepaths.first() {
+        notes.set(bak_filepath, note);
+    }
+
+    notes.save(&path)
 }
 
 /// Performs a copy
 fn run_backup(cli: &cli::Cli) -> Result<(), Error> {
-    let dir = cli.dir();
-    let app_data_dir = os::user_app_data_dir(true, BAK9.into())
-        .map_err(|e| Error::Generic(e.to_string()))?;
-    let is_app_data_dir = dir == app_data_dir;
+    backup(cli).map(|_| ())
+}
+
+/// The store that new backups of `file` are written to within `dir`.
+fn backup_dir_store(cli: &cli::Cli, file: &Path, dir: &Path) -> Box<dyn BackupStore> {
+    match cli.archive {
+        Some(archive) => Box::new(store::TarStore::new(file, dir, archive)
+            .with_encryption(cli.encrypt.clone())),
+        None => Box::new(store::SiblingDirStore::new(file, dir)
+            .with_trash(cli.trash)
+            .with_encryption(cli.encrypt.clone())
+            .with_copy_links(cli.no_dereference))
+    }
+}
+
+/// The app data mirror that backups of `file` are put in, if it is DIR or DIR can't be written to.
+fn backup_mirror_store(cli: &cli::Cli, file: &Path, app_data_dir: &Path) -> MirrorStore {
+    MirrorStore::new(file, app_data_dir)
+        .with_archive(cli.archive)
+        .with_trash(cli.trash)
+        .with_encryption(cli.encrypt.clone())
+        .with_copy_links(cli.no_dereference)
+}
+
+/// Whether `file` is excluded from backups by a policy, printing a notice if so.
+fn is_excluded(cli: &cli::Cli, file: &Path) -> Result<bool, Error> {
+    let policy_file = match policy::Policy::resolve(file)?.excluded_by(file) {
+        Some(policy_file) => policy_file,
+        None => return Ok(false)
+    };
+
+    if !cli.quiet {
+        eprintln!("{} {} is excluded by {}", "notice:".yellow(),
+            sanitize_path_str(file.to_str().expect(E_STR)).cyan(),
+            sanitize_path_str(policy_file.to_str().expect(E_STR)).cyan());
+    }
+
+    Ok(true)
+}
 
-    let dest_dir = if is_app_data_dir {
-        mirror_dir(&app_data_dir, &cli.file, true)?
+/// Prints the steps that a backup of FILE would perform, without performing them.
+fn run_backup_dry_run(cli: &cli::Cli) -> Result<(), Error> {
+    let file = cli.file()?;
+    if is_excluded(cli, file)? {
+        return Ok(())
+    }
+
+    // planned in the same store that the backup would be put in, so that a fallback is shown
+    let steps = dir_store(cli)?.plan_put(cli.num)?;
+
+    if steps.is_empty() {
+        println!("{} is unchanged", sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+    }
+
+    print_steps(&steps);
+    Ok(())
+}
+
+fn print_steps(steps: &[store::Step]) {
+    let path_str = |path: &Path| sanitize_path_str(path.to_str().expect(E_STR)).cyan();
+
+    for step in steps {
+        match step {
+            store::Step::Rename(from, to) => println!("{}  {} -> {}", "rename".yellow(), path_str(from), path_str(to)),
+            store::Step::Delete(path) => println!("{}  {}", "delete".red(), path_str(path)),
+            store::Step::Copy(from, to) => println!("{}    {} -> {}", "copy".green(), path_str(from), path_str(to))
+        }
+    }
+}
+
+/// Backs up FILE to DIR, returning the path of the new backup, if one was created.
+fn backup(cli: &cli::Cli) -> Result<Option<PathBuf>, Error> {
+    backup_file(cli, cli.file()?)
+}
+
+/// Backs up `file` to DIR, or beside it. See [backup].
+fn backup_file(cli: &cli::Cli, file: &Path) -> Result<Option<PathBuf>, Error> {
+    if is_excluded(cli, file)? {
+        return Ok(None)
+    }
+
+    if cli.no_dereference && cli.archive.is_some() && file.is_symlink() {
+        return Err(Error::Generic(format!("Unable to archive a backup of the symlink {}",
+            sanitize_path_str(file.to_str().expect(E_STR)).cyan())))
+    }
+
+    let dir = cli.dir_for(Some(file));
+    let app_data_dir = user_app_data_dir()?;
+
+    // stdin's backups were locked before it was staged
+    let _lock = match cli.name {
+        Some(_) => None,
+        None => Some(lock_backups(cli, file, &dir, &app_data_dir)?)
+    };
+    let mirror_store = backup_mirror_store(cli, file, &app_data_dir);
+
+    if cli.link && mirror_store.list()?.is_empty()
+        && (dir == app_data_dir || store::dir_store(file, &dir, cli.archive).list()?.is_empty())
+    {
+        link_history(cli, file, &app_data_dir)?;
+    }
+
+    let bak_filepath = if dir == app_data_dir {
+        mirror_store.put(cli.num)?
     } else {
-        dir
+        let store = FallbackStore::new(
+            backup_dir_store(cli, file, &dir),
+            Box::new(mirror_store));
+
+        let bak_filepath = store.put(cli.num)?;
+
+        match &bak_filepath {
+            Some(bak_filepath) if store.is_fallback(bak_filepath) && !cli.quiet => {
+                eprintln!("{} copied to {}", "notice:".yellow(),
+                    sanitize_path_str(bak_filepath.to_str().expect(E_STR)).cyan());
+            },
+            _ => {}
+        }
+
+        bak_filepath
     };
 
-    let bak_filepath = match determine_destination(&cli.file, &dest_dir, cli.num)? {
-        Some(filepath) => filepath,
-        None => return Ok(())
+    if let (Some(bak_filepath), true) = (&bak_filepath, cli.catalog) {
+        catalog::Catalog::user()?.record(file, bak_filepath)?;
+    }
+
+    // an unchanged file's note is attached to its latest backup, which is of the same contents
+    if let (Some(note), Some(store)) = (&cli.message, latest_store(cli, file, bak_filepath.as_deref())?) {
+        if bak_filepath.is_none() && !cli.quiet {
+            eprintln!("{} {} is unchanged, so the note was attached to its latest backup", "notice:".yellow(),
+                sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+        }
+
+        attach_note(store.as_ref(), note)?;
+    }
+
+    Ok(bak_filepath)
+}
+
+/// The store that holds the latest backup of `file`: either the one at `bak_filepath` that was
+/// just made, or the newest one if the backup was skipped.
+fn latest_store(cli: &cli::Cli, file: &Path, bak_filepath: Option<&Path>) -> Result<Option<Box<dyn BackupStore>>, Error> {
+    for store in file_stores(cli, file, true)? {
+        let newest = store.list()?.into_iter().next();
+        if newest.is_some() && (bak_filepath.is_none() || newest.as_deref() == bak_filepath) {
+            return Ok(Some(store))
+        }
+    }
+
+    Ok(None)
+}
+
+/// Backs up FILE, opens it in the user's editor and then shows the differences from the pre-edit
+/// backup. If FILE was not modified, the backup that was just made is discarded.
+fn run_edit(cli: &cli::Cli) -> Result<(), Error> {
+    let file = cli.file()?;
+    let bak_filepath = backup(cli)?;
+    let store = latest_store(cli, file, bak_filepath.as_deref())?;
+    let edited = os::run_editor(file);
+
+    let store = match store {
+        Some(store) => store,
+        None => return edited // excluded by policy
     };
 
-    match os::copy_file(&cli.file, &bak_filepath) {
-        Ok(_) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && !is_app_data_dir => {
-            let app_data_dir = os::user_app_data_dir(true, BAK9.into())
-                .map_err(|e| Error::Generic(e.to_string()))?;
+    let is_changed = with_readable_backup(cli, store.as_ref(), 0, |pre_edit_file| {
+        let is_changed = diff_files(file, pre_edit_file)?;
+        if is_changed && !cli.quiet {
+            os::print_diff(file, pre_edit_file, cli.diff_tool.as_deref())?;
+        }
 
-            let mirror_dir = mirror_dir(&app_data_dir, &cli.file, true)?;
+        Ok(is_changed)
+    })?;
 
-            let home_bak_filepath = match determine_destination(&cli.file, &mirror_dir, cli.num)? {
-                Some(filepath) => filepath,
-                None => return Ok(())
-            };
+    if !is_changed {
+        if bak_filepath.is_some() {
+            store.discard()?;
+        }
 
-            os::copy_file(&cli.file, &home_bak_filepath)
-                .map_err(|_| Error::copy(&cli.file, &home_bak_filepath, e))?;
+        if !cli.quiet {
+            println!("No changes to {}", sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+        }
+    }
+
+    edited
+}
 
+/// Backs up every file, runs `command` and then reports the files that it changed, along with their
+/// differences. If the command fails and `restore_on_failure` is set, the changed files are restored.
+fn run_exec(cli: &cli::Cli, files: &[PathBuf], restore_on_failure: bool, command: &[String]) -> Result<(), Error> {
+    if cli.file.is_none() && files.is_empty() {
+        return Err(Error::Generic("FILE is required".to_string()))
+    }
+
+    let mut snapshots = Vec::new();
+
+    for file in cli.file.iter().chain(files) {
+        if snapshots.iter().any(|(snapshot_file, _)| snapshot_file == file) {
+            continue
+        }
+
+        // files that are excluded by policy have no backup to compare with
+        let bak_filepath = backup_file(cli, file)?;
+        if let Some(store) = latest_store(cli, file, bak_filepath.as_deref())? {
+            snapshots.push((file.clone(), store));
+        }
+    }
+
+    let (program, args) = command.split_first()
+        .ok_or_else(|| Error::Generic("CMD is required".to_string()))?;
+
+    let status = std::process::Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| Error::Generic(format!("Unable to run {program}: {e}")))?;
+
+    let mut changed = Vec::new();
+
+    for (file, store) in &snapshots {
+        let file_str = sanitize_path_str(file.to_str().expect(E_STR));
+
+        if !file.exists() {
             if !cli.quiet {
-                eprintln!("{} copied to {}", "notice:".yellow(),
-                    sanitize_path_str(home_bak_filepath.to_str().expect(E_STR)).cyan());
+                println!("{} {}", "deleted:".yellow(), file_str.cyan());
             }
+        } else {
+            let is_changed = with_readable_backup(cli, store.as_ref(), 0, |bak_filepath| {
+                let is_changed = diff_files(file, bak_filepath)?;
+                if is_changed && !cli.quiet {
+                    println!("{} {}", "changed:".yellow(), file_str.cyan());
+                    os::print_diff(file, bak_filepath, cli.diff_tool.as_deref())?;
+                }
+
+                Ok(is_changed)
+            })?;
+
+            if !is_changed {
+                continue
+            }
+        }
+
+        changed.push((file, store));
+    }
 
-            Ok(())
-        },
-        Err(e) => Err(Error::copy(&cli.file, &bak_filepath, e))
+    if status.success() {
+        return Ok(())
     }
+
+    if restore_on_failure {
+        for (file, store) in changed {
+            restore_backup(cli, store.as_ref(), 0, file)?;
+
+            if !cli.quiet {
+                println!("{} {}", "restored:".green(), sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+            }
+        }
+    }
+
+    Err(Error::Generic(format!("{program} exited with {status}")))
 }
 
-fn determine_destination(source_file: &Path, dest_dir: &Path, max: u8) -> Result<Option<PathBuf>, Error> {
-    let source_filename = source_file.filename_str().expect(E_FILENAME);
-    let last_bak = find_last_bak(source_file, dest_dir);
+/// Locks the backups of `file` in `dir`, or in its mirror if `dir` is the app data directory or
+/// can't be written to.
+fn lock_backups(cli: &cli::Cli, file: &Path, dir: &Path, app_data_dir: &Path) -> Result<lock::Lock, Error> {
+    let contention = if cli.no_wait { lock::Contention::Fail } else { lock::Contention::Wait };
+    let on_wait = || if !cli.quiet {
+        eprintln!("{} waiting for another backup of {}", "notice:".yellow(),
+            sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+    };
 
-    if let Some(last_bak_filepath) = &last_bak {
-        if !diff_files(last_bak_filepath, source_file)? {
-            return Ok(None)
+    if dir != app_data_dir {
+        match lock::Lock::acquire(file, dir, contention, on_wait) {
+            Err(e) if e.is_permission_denied() => {},
+            result => return result
         }
     }
 
-    let bak_filepath = if let Some(last_bak_filepath) = &last_bak {
-        if max == 1 {
-            wipe(source_file, dest_dir)?;
+    lock::Lock::acquire(file, &mirror_dir(app_data_dir, file, true)?, contention, || {})
+}
+
+/// Backs up FILE, and then again every time that it changes. A backup is made once no further
+/// changes have been seen for the debounce interval, so that a burst of writes results in a single
+/// backup. Runs until interrupted.
+fn run_watch(cli: &cli::Cli) -> Result<(), Error> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let file = cli.file()?;
+    let debounce = std::time::Duration::from_millis(cli.debounce);
+
+    let backup_and_report = || match backup(cli) {
+        Ok(Some(bak_filepath)) if !cli.quiet => println!("{} {}", "backed up to".green(),
+            sanitize_path_str(bak_filepath.to_str().expect(E_STR)).cyan()),
+        Ok(_) => {},
+        // keep watching, the next write may succeed
+        Err(err) => eprintln!("{} {err}", "error:".red())
+    };
 
-            dest_dir
-                .join(&source_filename)
-                .append_extension(BAK)
-        } else if last_bak_filepath.extension().expect("Expected .bak") == BAK {
-            shift_bak_files(&source_file, &dest_dir, max)?;
+    backup_and_report();
 
-            let bak1_filepath = dest_dir
-                .join(&source_filename)
-                .append_extension(BAK_1);
+    // editors often replace FILE rather than writing to it, so watch its directory instead
+    let dir = file.parent().expect("Expected parent directory");
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)
+        .map_err(|e| Error::Generic(format!("Unable to watch {}: {e}", dir.to_str().expect(E_STR).cyan())))?;
 
-            fs::rename(last_bak_filepath, &bak1_filepath)
-                .map_err(|e| Error::io(IoOp::Rename, &bak1_filepath, e))?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|e| Error::Generic(format!("Unable to watch {}: {e}", dir.to_str().expect(E_STR).cyan())))?;
 
-            dest_dir
-                .join(&source_filename)
-                .append_extension(BAK_0)
-        } else {
-            shift_bak_files(source_file, dest_dir, max)?;
+    if !cli.quiet {
+        println!("Watching {} for changes", sanitize_path_str(file.to_str().expect(E_STR)).cyan());
+    }
 
-            dest_dir 
-                .join(&source_filename)
-                .append_extension(BAK_0)
+    let is_change = |event: &notify::Result<notify::Event>| match event {
+        Ok(event) => !matches!(event.kind, EventKind::Access(_))
+            && event.paths.iter().any(|path| path == file),
+        Err(_) => false
+    };
+
+    while let Ok(event) = receiver.recv() {
+        if !is_change(&event) {
+            continue
+        }
+
+        // wait for the writes to settle, ignoring events on other files in the directory
+        let mut deadline = std::time::Instant::now() + debounce;
+        loop {
+            match receiver.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())) {
+                Ok(event) if is_change(&event) => deadline = std::time::Instant::now() + debounce,
+                Ok(_) => {},
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => break,
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Ok(())
+            }
+        }
+
+        // FILE may have been deleted, or be between a delete and a rename
+        if file.is_file() {
+            backup_and_report();
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the backup history of `old_file` to `new_file`: the sibling backups in the old file's
+/// directory, as well as its mirror in the app data directory. Returns the number of backup files moved.
+fn relocate_history(old_file: &Path, new_file: &Path, app_data_dir: &Path) -> Result<usize, Error> {
+    let old_dir = old_file.parent().expect("Expected parent directory");
+    let new_dir = new_file.parent().expect("Expected parent directory");
+    let mut moved = store::relocate(old_file, old_dir, new_file, new_dir)?;
+
+    let old_mirror_dir = determine_mirror_dir(app_data_dir, old_file);
+    if old_mirror_dir.is_dir() {
+        let new_mirror_dir = mirror_dir(app_data_dir, new_file, true)?;
+        moved += store::relocate(old_file, &old_mirror_dir, new_file, &new_mirror_dir)?;
+
+        remove_app_data_subdir(app_data_dir, &old_mirror_dir)?;
+        remove_app_data_subdir(app_data_dir, &new_mirror_dir)?;
+    }
+
+    Ok(moved)
+}
+
+/// The steps that [relocate_history] would perform.
+fn plan_relocate_history(old_file: &Path, new_file: &Path, app_data_dir: &Path) -> Result<Vec<store::Step>, Error> {
+    let old_dir = old_file.parent().expect("Expected parent directory");
+    let new_dir = new_file.parent().expect("Expected parent directory");
+    let mut steps = store::plan_relocate(old_file, old_dir, new_file, new_dir)?;
+
+    let old_mirror_dir = determine_mirror_dir(app_data_dir, old_file);
+    let new_mirror_dir = determine_mirror_dir(app_data_dir, new_file);
+    steps.extend(store::plan_relocate(old_file, &old_mirror_dir, new_file, &new_mirror_dir)?);
+
+    Ok(steps)
+}
+
+fn run_move(cli: &cli::Cli, old_file: &Path, new_file: &Path) -> Result<(), Error> {
+    if cli.dry_run {
+        print_steps(&plan_relocate_history(old_file, new_file, &user_app_data_dir()?)?);
+        return Ok(())
+    }
+
+    let moved = relocate_history(old_file, new_file, &user_app_data_dir()?)?;
+
+    if !cli.quiet {
+        println!("Moved {moved} backups of {old} to {new}",
+            old = sanitize_path_str(old_file.to_str().expect(E_STR)).cyan(),
+            new = sanitize_path_str(new_file.to_str().expect(E_STR)).cyan());
+    }
+
+    Ok(())
+}
+
+/// Finds an orphaned mirror source whose most recent backup has the same contents as `file`,
+/// which is likely to be where the file was moved from.
+fn find_moved_source(file: &Path, app_data_dir: &Path) -> Result<Option<mirror::MirroredSource>, Error> {
+    let size = file.metadata()
+        .map_err(|e| Error::io(IoOp::Read, file, e))?
+        .len();
+    let hash = hash_file(file)?;
+
+    for source in mirror::walk(app_data_dir)? {
+        if !source.is_orphan() {
+            continue
+        }
+
+        for store in mirror::stores(&source) {
+            let bak_filepath = match store.get(0) {
+                Ok(bak_filepath) => bak_filepath,
+                Err(_) => continue
+            };
+
+            if bak_filepath.metadata().is_ok_and(|metadata| metadata.len() == size) && hash_file(&bak_filepath)? == hash {
+                return Ok(Some(source))
+            }
         }
-    } else { 
-        dest_dir
-            .join(&source_filename)
-            .append_extension(BAK)
+    }
+
+    Ok(None)
+}
+
+/// Offers to link the backup history of a moved file, before its first backup at the new path.
+fn link_history(cli: &cli::Cli, file: &Path, app_data_dir: &Path) -> Result<(), Error> {
+    let source = match find_moved_source(file, app_data_dir)? {
+        Some(source) => source,
+        None => return Ok(())
     };
 
-    Ok(Some(bak_filepath))
+    let question = format!("{} matches the backups of {}. Link its backup history?",
+        sanitize_path_str(file.to_str().expect(E_STR)).cyan(),
+        sanitize_path_str(source.source_file.to_str().expect(E_STR)).cyan());
+
+    if confirm(&question, cli.force) {
+        relocate_history(&source.source_file, file, app_data_dir)?;
+    }
+
+    Ok(())
+}
+
+fn run_catalog(mut filter: catalog::Filter) -> Result<(), Error> {
+    // prefixes are matched against absolute source paths
+    if let Some(prefix) = &filter.prefix {
+        filter.prefix = Some(prefix.canonicalize()
+            .or_else(|_| std::env::current_dir().map(|dir| dir.join(prefix)))
+            .map_err(|e| Error::io(IoOp::Read, prefix, e))?);
+    }
+
+    for entry in catalog::Catalog::user()?.query(&filter)? {
+        println!("{time}  {source} -> {dest}  {size} bytes  {user}@{host}  {hash}",
+            time = entry.time.yellow(),
+            source = sanitize_path_str(entry.source.to_str().expect(E_STR)).cyan(),
+            dest = sanitize_path_str(entry.destination.to_str().expect(E_STR)).green(),
+            size = entry.size,
+            user = entry.user,
+            host = entry.host,
+            hash = entry.hash.get(..12).unwrap_or(&entry.hash));
+    }
+
+    Ok(())
+}
+
+/// Formats a number of bytes with a binary unit, e.g. `1.5 KiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B")
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Formats a timestamp in local time, e.g. `2024-01-31 12:00:00`.
+pub fn format_time(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
 }
 
 pub fn sanitize_path_str(path: &str) -> &str {
@@ -477,7 +1566,7 @@ pub fn sanitize_windows_path_str(path: &str) -> &str {
     path.trim_start_matches("\\\\?\\")
 }
 
-fn determine_mirror_dir(base_dir: &Path, src_file: &Path) -> Result<PathBuf, Error> {
+fn determine_mirror_dir(base_dir: &Path, src_file: &Path) -> PathBuf {
     let src_dir = src_file.parent().expect("Expected parent directory");
     let mut mirror_dir = base_dir.to_path_buf();
 
@@ -493,20 +1582,38 @@ fn determine_mirror_dir(base_dir: &Path, src_file: &Path) -> Result<PathBuf, Err
 
         // windows drives (C:, D:, etc)
         if dirname.chars().count() == 2 && dirname.chars().nth(1).unwrap() == ':' {
-            mirror_dir.push(dirname.chars().nth(0).unwrap().to_string());
+            mirror_dir.push(dirname.chars().next().unwrap().to_string());
         } else {
             mirror_dir.push(dirname);
         }
     }
 
-    Ok(mirror_dir)
+    mirror_dir
+}
+
+/// Reverses [determine_mirror_dir], reconstructing the source directory that a mirror directory
+/// was created for.
+fn determine_source_dir(base_dir: &Path, mirror_dir: &Path) -> Option<PathBuf> {
+    let mut dirnames = mirror_dir.strip_prefix(base_dir).ok()?
+        .components()
+        .map(|component| component.as_os_str());
+
+    let mut src_dir = if cfg!(target_os = "windows") {
+        // the first component is the drive letter
+        PathBuf::from(format!("{}:\\", dirnames.next()?.to_str().expect(E_STR)))
+    } else {
+        PathBuf::from("/")
+    };
+
+    src_dir.extend(dirnames);
+    Some(src_dir)
 }
 
 pub fn mirror_dir(base_dir: &Path, src_file: &Path, mkdir: bool) -> Result<PathBuf, Error> {
-    let mirror_dir = determine_mirror_dir(base_dir, src_file)?;
+    let mirror_dir = determine_mirror_dir(base_dir, src_file);
 
     if !mirror_dir.is_dir() && mkdir {
-        fs::create_dir_all(&mirror_dir)
+        os::create_private_dir_all(&mirror_dir)
             .map_err(|e| Error::io(IoOp::Create, &mirror_dir, e))?
     }
 
@@ -514,66 +1621,83 @@ pub fn mirror_dir(base_dir: &Path, src_file: &Path, mkdir: bool) -> Result<PathB
         .map_err(|e| Error::io(IoOp::Read, &mirror_dir, e))
 }
 
+/// Calculates the hex encoded SHA-256 hash of a file's contents.
+fn hash_file(path: &Path) -> Result<String, Error> {
+    use sha2::Digest;
+
+    let mut file = fs::File::open(path)
+        .map_err(|e| Error::io(IoOp::Read, path, e))?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .map_err(|e| Error::io(IoOp::Read, path, e))?;
+
+    Ok(hasher.finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
 fn diff_files(a: &Path, b: &Path) -> Result<bool, Error> {
     // check to see if a backup is necessary, using a file diff
-    let mut file = std::fs::File::open(a)
+    let file = std::fs::File::open(a)
         .map_err(|e| Error::io(IoOp::Read, a, e))?;
-    let mut last_bak = std::fs::File::open(b)
+    let last_bak = std::fs::File::open(b)
         .map_err(|e| Error::io(IoOp::Read, b, e))?;
 
-    Ok(!file_diff::diff_files(&mut file, &mut last_bak))
+    let len = |file: &fs::File, path: &Path| file.metadata()
+        .map(|metadata| metadata.len())
+        .map_err(|e| Error::io(IoOp::Read, path, e));
+
+    if len(&file, a)? != len(&last_bak, b)? {
+        return Ok(true)
+    }
+
+    diff_readers(file, last_bak)
+        .map_err(|e| Error::io(IoOp::Read, a, e))
 }
 
-/// Increments the filename extension of all `.bak.N` files in the directory.
-fn shift_bak_files(file: &Path, dir: &Path, num: u8) -> Result<(), Error> {
-    let mut bak_filepaths = list_bak_n_files(file, dir)?;
+/// Whether two streams differ, comparing them a block at a time.
+pub(crate) fn diff_readers(mut a: impl std::io::Read, mut b: impl std::io::Read) -> std::io::Result<bool> {
+    const BLOCK_SIZE: usize = 64 * 1024;
+    let mut block_a = vec![0u8; BLOCK_SIZE];
+    let mut block_b = vec![0u8; BLOCK_SIZE];
 
-    // prune all excess backups
-    if bak_filepaths.len() >= num as usize {
-        let prune_amount = bak_filepaths.len() - num as usize + 1;
-        for _i in 0..prune_amount {
-            let bak_filepath = bak_filepaths.pop().expect("Expected array value");
-            std::fs::remove_file(&bak_filepath)
-                .map_err(|e| Error::io(IoOp::Delete, &bak_filepath, e))?;
+    loop {
+        let len_a = read_block(&mut a, &mut block_a)?;
+        let len_b = read_block(&mut b, &mut block_b)?;
+
+        if block_a[..len_a] != block_b[..len_b] {
+            return Ok(true)
+        } else if len_a == 0 {
+            return Ok(false)
         }
     }
+}
 
-    let bak_n_file_pattern = file
-        .append_extension(BAK_DOT)
-        .filename_string().expect(E_FILENAME);
+/// Fills `block` unless the end of the stream is reached first, returning the length read.
+fn read_block(reader: &mut impl std::io::Read, block: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
 
-    // shift each up by 1
-    let source_filename = file.filename_string().expect(E_FILENAME);
-    for bak_filepath in bak_filepaths.into_iter().rev() {
-        let n = bak_filepath.filename_str().expect(E_FILENAME)
-            .trim_start_matches(&bak_n_file_pattern)
-            .parse::<u32>()
-            .expect("Expected numeric extension");
-        let bak_next_filepath = dir.join(&source_filename)
-            .append_extension(BAK)
-            .append_extension((n + 1).to_string().as_str());
-        fs::rename(bak_filepath, &bak_next_filepath)
-            .map_err(|e| Error::io(IoOp::Write, &bak_next_filepath, e))?;
+    while len < block.len() {
+        match reader.read(&mut block[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
     }
 
-    Ok(())
+    Ok(len)
 }
 
-/// Returns either a `.bak` or `.bak.0` file if it exists.
-fn find_last_bak(file: &Path, dir: &Path) -> Option<PathBuf> {
-    let bak_file = dir.join(file.filename_string().expect(E_FILENAME))
-        .append_extension(BAK);
-    if bak_file.exists() {
-        Some(bak_file)
-    } else {
-        let bak0_file = dir.join(file.filename_string().expect(E_FILENAME))
-            .append_extension(BAK_0);
-        if bak0_file.exists() {
-            Some(bak0_file)
-        } else {
-            None
-        }
-    }
+/// An empty directory for the test `name`, beneath the target directory so that tests never
+/// share one or leave files behind in the system's.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("tmp").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
 #[cfg(test)]
@@ -585,17 +1709,52 @@ mod tests {
     fn test_determine_mirror_dir() {
         let base_dir = "/home/dev/.local/share/bak9";
         let src_file = "/home/dev/tmp/source.txt";
-        let mirror_dir = determine_mirror_dir(Path::new(base_dir), Path::new(src_file)).unwrap();
+        let mirror_dir = determine_mirror_dir(Path::new(base_dir), Path::new(src_file));
         assert_eq!(Path::new("/home/dev/.local/share/bak9/home/dev/tmp"), mirror_dir);
     }
     
+    #[test]
+    fn test_format_size() {
+        assert_eq!("0 B", format_size(0));
+        assert_eq!("1023 B", format_size(1023));
+        assert_eq!("1.5 KiB", format_size(1536));
+        assert_eq!("2.0 GiB", format_size(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_bisect() {
+        let holds = |until: usize| move |position: usize| Ok(position < until);
+        assert_eq!(0, bisect(5, holds(0)).unwrap());
+        assert_eq!(3, bisect(5, holds(3)).unwrap());
+        assert_eq!(5, bisect(5, holds(9)).unwrap());
+        assert_eq!(0, bisect(0, holds(1)).unwrap());
+    }
+
+    #[test]
+    fn test_diffstat() {
+        assert_eq!((0, 0), diffstat("a\nb\n", "a\nb\n"));
+        assert_eq!((2, 0), diffstat("", "a\nb\n"));
+        assert_eq!((1, 1), diffstat("a\nb\n", "a\nc\n"));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_determine_source_dir() {
+        let base_dir = Path::new("/home/dev/.local/share/bak9");
+        let src_file = Path::new("/home/dev/tmp/source.txt");
+        let mirror_dir = determine_mirror_dir(base_dir, src_file);
+        assert_eq!(Some(PathBuf::from("/home/dev/tmp")), determine_source_dir(base_dir, &mirror_dir));
+        assert_eq!(Some(PathBuf::from("/")), determine_source_dir(base_dir, base_dir));
+        assert_eq!(None, determine_source_dir(base_dir, Path::new("/tmp")));
+    }
+
     #[cfg(target_os = "windows")]
     #[test]
     fn test_determine_mirror_dir_windows() {
         // test a windows path with path extensions
         let base_dir = "\\\\?\\C:\\Users\\dev\\AppData\\Local\\bak9";
         let src_file = "\\\\?\\C:\\Users\\dev\\tmp\\source.txt";
-        let mirror_dir = determine_mirror_dir(Path::new(base_dir), Path::new(src_file)).unwrap();
+        let mirror_dir = determine_mirror_dir(Path::new(base_dir), Path::new(src_file));
         assert_eq!("\\\\?\\C:\\Users\\dev\\AppData\\Local\\bak9\\C\\Users\\dev\\tmp", mirror_dir.to_str().unwrap());
     }
 }
\ No newline at end of file
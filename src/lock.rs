@@ -0,0 +1,120 @@
+//! Advisory locking, so that simultaneous backups of the same file don't rotate over each other.
+//!
+//! The lock is held on a `.bak9.lock` file beside the backups, e.g. `foo.conf.bak9.lock`, and the
+//! file is deleted again once the lock is released.
+
+use std::{fs, path::{Path, PathBuf}};
+use crate::{Error, IoOp, PathExt, BAK9, E_FILENAME};
+
+const LOCK: &str = "lock";
+
+/// An exclusive lock on the backups of a source file within a directory. Released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+    file: fs::File
+}
+
+/// What to do when another process holds the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Contention {
+    /// Block until the lock is released, calling `on_wait` first
+    Wait,
+    /// Fail immediately
+    Fail
+}
+
+impl Lock {
+    /// Locks the backups of `source_file` within `dir`. If the lock is held by another process,
+    /// either waits for it after calling `on_wait`, or fails.
+    pub fn acquire(source_file: &Path, dir: &Path, contention: Contention, on_wait: impl FnOnce()) -> Result<Self, Error> {
+        let path = lock_path(source_file, dir);
+        let mut on_wait = Some(on_wait);
+
+        loop {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .map_err(|e| Error::io(IoOp::Create, &path, e))?;
+
+            match file.try_lock() {
+                Ok(_) => {},
+                Err(fs::TryLockError::WouldBlock) if contention == Contention::Wait => {
+                    if let Some(on_wait) = on_wait.take() {
+                        on_wait();
+                    }
+
+                    file.lock()
+                        .map_err(|e| Error::io(IoOp::Write, &path, e))?;
+                },
+                Err(fs::TryLockError::WouldBlock) => {
+                    return Err(Error::Generic(format!("Backups of {} are locked by another process: {}",
+                        source_file.filename_str().expect(E_FILENAME), path.to_str().expect(crate::E_STR))))
+                },
+                Err(fs::TryLockError::Error(e)) => return Err(Error::io(IoOp::Write, &path, e))
+            }
+
+            // the previous holder may have deleted the file while this process waited for it
+            if is_same_file(&file, &path) {
+                return Ok(Self { path, file })
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // delete while still locked, so that a waiting process notices and locks a new file instead
+        if cfg!(unix) {
+            let _ = fs::remove_file(&self.path);
+        }
+
+        let _ = self.file.unlock();
+    }
+}
+
+/// The lock file for the backups of `source_file` within `dir`.
+pub fn lock_path(source_file: &Path, dir: &Path) -> PathBuf {
+    dir.join(source_file.filename_str().expect(E_FILENAME))
+        .append_extension(BAK9)
+        .append_extension(LOCK)
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &fs::File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &fs::File, _path: &Path) -> bool {
+    // lock files are never deleted
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let dir = crate::test_dir("test_lock_acquire");
+        let source = dir.join("source.txt");
+
+        let lock = Lock::acquire(&source, &dir, Contention::Fail, || {}).unwrap();
+        assert!(lock_path(&source, &dir).exists());
+        assert!(Lock::acquire(&source, &dir, Contention::Fail, || {}).is_err());
+
+        drop(lock);
+        let lock = Lock::acquire(&source, &dir, Contention::Fail, || {}).unwrap();
+        drop(lock);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
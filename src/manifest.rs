@@ -0,0 +1,111 @@
+//! Manifests that record a value for each backup in a store, beside the backups.
+//!
+//! Each value is keyed by its backup's name, which is unique within the store: `bak.N` for a
+//! `.bak.N` file, or the member name within an archive. Rotating `.bak.N` files renames their keys
+//! in the same journal, so that each value follows its backup.
+
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use crate::{mirror, store, Error, IoOp, PathExt};
+
+/// A value for each backup in a store, such as its note, keyed by the backup's name.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq)]
+pub struct Manifest(BTreeMap<String, String>);
+
+impl Manifest {
+    /// Reads the manifest at `path`, if it exists.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Self::default())
+        }
+
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::io(IoOp::Read, path, e))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| Error::Generic(format!("Invalid manifest {}: {e}", path.to_str().expect(crate::E_STR))))
+    }
+
+    /// Writes the manifest to `path`, or removes it if it's empty.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return remove(path)
+        }
+
+        let contents = toml::to_string(self)
+            .map_err(|e| Error::Generic(e.to_string()))?;
+
+        fs::write(path, contents)
+            .map_err(|e| Error::io(IoOp::Write, path, e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The value of the backup at `bak_filepath`.
+    pub fn get(&self, bak_filepath: &Path) -> Option<&str> {
+        self.0.get(&key(bak_filepath)?).map(String::as_str)
+    }
+
+    /// Sets the value of the backup at `bak_filepath`.
+    pub fn set(&mut self, bak_filepath: &Path, value: &str) {
+        if let Some(key) = key(bak_filepath) {
+            self.0.insert(key, value.to_string());
+        }
+    }
+
+    /// Forgets the value of the backup at `bak_filepath`.
+    pub fn unset(&mut self, bak_filepath: &Path) {
+        if let Some(key) = key(bak_filepath) {
+            self.0.remove(&key);
+        }
+    }
+
+    /// Forgets the values of any backups that aren't in `bak_filepaths`, since they have been pruned.
+    pub fn retain(&mut self, bak_filepaths: &[PathBuf]) {
+        let keys: Vec<String> = bak_filepaths.iter()
+            .filter_map(|bak_filepath| key(bak_filepath))
+            .collect();
+        self.0.retain(|key, _| keys.contains(key));
+    }
+
+    /// Follows the `.bak.N` files of `source_filename` through `renames`, in order. The values of
+    /// any that are renamed aside to be pruned are dropped.
+    pub(crate) fn rotate(&self, source_filename: &str, renames: &[(String, String)]) -> Self {
+        let prefix = format!("{source_filename}.");
+
+        Self(self.0.iter()
+            .filter_map(|(key, value)| {
+                let filename = renames.iter()
+                    .fold(format!("{prefix}{key}"), |filename, (from, to)| if *from == filename { to.clone() } else { filename });
+
+                store::source_filename(&filename)
+                    .filter(|name| *name == source_filename)
+                    .map(|_| (filename[prefix.len()..].to_string(), value.clone()))
+            })
+            .collect())
+    }
+}
+
+/// The key of the backup at `bak_filepath`: the member name of an archived backup,
+/// otherwise the extension that follows the source filename, e.g. `bak.1`.
+fn key(bak_filepath: &Path) -> Option<String> {
+    let filename = bak_filepath.filename_str()?;
+
+    if bak_filepath.parent().and_then(mirror::archive_of).is_some() {
+        return Some(filename.to_string())
+    }
+
+    let source_filename = store::source_filename(filename)?;
+    Some(filename[source_filename.len() + 1..].to_string())
+}
+
+/// Removes the manifest at `path`.
+pub fn remove(path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        fs::remove_file(path)
+            .map_err(|e| Error::io(IoOp::Delete, path, e))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! Walks the mirror tree within the user's app data directory, reconstructing the source files
+//! that have been backed up to it.
+
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use crate::{store::{self, Archive, BackupStore, TarStore}, Error, IoOp, PathExt, BAK9};
+
+/// A source file that has backups within the mirror tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirroredSource {
+    /// The reconstructed path of the source file, which may no longer exist
+    pub source_file: PathBuf,
+    pub mirror_dir: PathBuf,
+    /// Every `.bak`, `.bak.N` and `.bak9` archive file of the source
+    pub bak_filepaths: Vec<PathBuf>
+}
+
+/// Totals for the backups of a single source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub count: usize,
+    pub size: u64,
+    pub newest: Option<SystemTime>
+}
+
+impl MirroredSource {
+    /// Totals the backups. Archives count each of their members.
+    pub fn summary(&self) -> Result<Summary, Error> {
+        let mut summary = Summary::default();
+
+        for bak_filepath in &self.bak_filepaths {
+            let metadata = bak_filepath.metadata()
+                .map_err(|e| Error::io(IoOp::Read, bak_filepath, e))?;
+
+            summary.size += metadata.len();
+
+            match archive_of(bak_filepath) {
+                Some(archive) => {
+                    let members = TarStore::new(&self.source_file, &self.mirror_dir, archive).members()?;
+                    summary.count += members.len();
+                    summary.newest = summary.newest.max(members.iter()
+                        .map(|member| UNIX_EPOCH + Duration::from_secs(member.mtime))
+                        .max());
+                },
+                None => {
+                    summary.count += 1;
+                    summary.newest = summary.newest.max(metadata.modified().ok());
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Whether the source file no longer exists.
+    pub fn is_orphan(&self) -> bool {
+        !self.source_file.exists()
+    }
+}
+
+/// The archive format of a `.bak9` archive file, if it is one.
+pub(crate) fn archive_of(bak_filepath: &Path) -> Option<Archive> {
+    let filename = bak_filepath.filename_str()?;
+
+    [Archive::Tar, Archive::TarGz].into_iter()
+        .find(|archive| filename.ends_with(&format!(".{BAK9}.{}", archive.extension())))
+}
+
+/// Finds every source file with backups in the mirror tree, ordered by path.
+pub fn walk(app_data_dir: &Path) -> Result<Vec<MirroredSource>, Error> {
+    let mut sources = Vec::new();
+    walk_dir(app_data_dir, app_data_dir, &mut sources)?;
+    sources.sort_by(|a, b| a.source_file.cmp(&b.source_file));
+    Ok(sources)
+}
+
+fn walk_dir(app_data_dir: &Path, dir: &Path, sources: &mut Vec<MirroredSource>) -> Result<(), Error> {
+    let src_dir = match crate::determine_source_dir(app_data_dir, dir) {
+        Some(src_dir) => src_dir,
+        None => return Ok(())
+    };
+
+    let mut bak_filepaths: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+
+    let entries = fs::read_dir(dir)
+        .map_err(|e| Error::io(IoOp::Read, dir, e))?;
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue
+        };
+
+        if file_type.is_dir() {
+            // stdin's backups have no source file
+            if path != app_data_dir.join(crate::STDIN_DIR) {
+                walk_dir(app_data_dir, &path, sources)?;
+            }
+        } else if file_type.is_file() {
+            if let Some(source_filename) = path.filename_str().and_then(store::source_filename) {
+                bak_filepaths.entry(source_filename.to_owned())
+                    .or_default()
+                    .push(path);
+            }
+        }
+    }
+
+    for (source_filename, mut paths) in bak_filepaths {
+        paths.sort();
+        sources.push(MirroredSource {
+            source_file: src_dir.join(source_filename),
+            mirror_dir: dir.to_path_buf(),
+            bak_filepaths: paths
+        });
+    }
+
+    Ok(())
+}
+
+/// The stores that hold the backups of a mirrored source.
+pub fn stores(source: &MirroredSource) -> Vec<Box<dyn BackupStore>> {
+    let mut stores = Vec::new();
+
+    if source.bak_filepaths.iter().any(|path| archive_of(path).is_none()) {
+        stores.push(store::dir_store(&source.source_file, &source.mirror_dir, None));
+    }
+
+    for archive in [Archive::Tar, Archive::TarGz] {
+        if source.bak_filepaths.iter().any(|path| archive_of(path) == Some(archive)) {
+            stores.push(store::dir_store(&source.source_file, &source.mirror_dir, Some(archive)));
+        }
+    }
+
+    stores
+}
@@ -0,0 +1,101 @@
+//! Notes attached to backups with `-m`, e.g. `bak -m "before enabling TLS" nginx.conf`.
+//!
+//! The notes on the backups in a store are kept in a manifest beside them, e.g.
+//! `nginx.conf.bak9.notes`, or `nginx.conf.bak9.tar.notes` for an archive. Each note is keyed by
+//! its backup's name, which is unique within the store: `bak.N` for a `.bak.N` file, or the member
+//! name within an archive. Rotating `.bak.N` files renames their keys in the same journal, so that
+//! each note follows its backup.
+
+use std::path::{Path, PathBuf};
+use crate::{manifest::{self, Manifest}, mirror, store::{Archive, BackupStore}, Error, PathExt, BAK9, E_FILENAME};
+
+pub(crate) const NOTES: &str = "notes";
+
+/// The notes on the backups in a store.
+pub type Notes = Manifest;
+
+/// The manifest of the notes on the `.bak.N` files of `source_file` within `dir`.
+fn dir_manifest_path(source_file: &Path, dir: &Path) -> PathBuf {
+    manifest_paths(source_file, dir).remove(0)
+}
+
+/// The manifest of the notes on the backups in `store`.
+pub fn manifest_path(store: &dyn BackupStore) -> PathBuf {
+    let location = store.location();
+    match mirror::archive_of(&location) {
+        Some(_) => location.append_extension(NOTES),
+        None => dir_manifest_path(store.source_file(), &location)
+    }
+}
+
+/// Every manifest that may hold notes on the backups of `source_file` within `dir`: that of its
+/// `.bak` files, followed by those of its archives.
+pub(crate) fn manifest_paths(source_file: &Path, dir: &Path) -> Vec<PathBuf> {
+    let filename = format!("{}.{BAK9}", source_file.filename_str().expect(E_FILENAME));
+
+    std::iter::once(dir.join(format!("{filename}.{NOTES}")))
+        .chain([Archive::Tar, Archive::TarGz].into_iter()
+            .map(|archive| dir.join(format!("{filename}.{}.{NOTES}", archive.extension()))))
+        .collect()
+}
+
+/// Forgets the notes in the manifest at `path`.
+pub fn remove(path: &Path) -> Result<(), Error> {
+    manifest::remove(path)
+}
+
+/// Forgets the notes on every backup of `source_file` within `dir`.
+pub fn remove_all(source_file: &Path, dir: &Path) -> Result<(), Error> {
+    manifest_paths(source_file, dir).iter()
+        .try_for_each(|path| remove(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn test_notes() {
+        let dir = crate::test_dir("test_notes");
+
+        let path = dir.join("nginx.conf.bak9.notes");
+        let older = dir.join("nginx.conf.bak.1");
+        let newer = dir.join("nginx.conf.bak.0");
+        let member = dir.join("nginx.conf.bak9.tar").join("nginx.conf.1700000000");
+
+        let mut notes = Notes::default();
+        notes.set(&older, "before enabling TLS");
+        notes.set(&newer, "after enabling TLS");
+        notes.set(&member, "archived");
+        notes.save(&path).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("\"bak.1\" = \"before enabling TLS\""));
+
+        let mut notes = Notes::load(&path).unwrap();
+        assert_eq!(Some("before enabling TLS"), notes.get(&older));
+        assert_eq!(Some("archived"), notes.get(&member));
+        assert_eq!(None, notes.get(&dir.join("nginx.conf.bak")));
+
+        // a rotation keeping at most two backups prunes bak.1 and shifts bak.0 into its place
+        let renames = [
+            ("nginx.conf.bak.1".to_string(), "nginx.conf.bak.1.pruned".to_string()),
+            ("nginx.conf.bak.0".to_string(), "nginx.conf.bak.1".to_string())
+        ];
+        let rotated = notes.rotate("nginx.conf", &renames);
+        assert_eq!(Some("after enabling TLS"), rotated.get(&older));
+        assert_eq!(None, rotated.get(&newer));
+
+        notes.retain(std::slice::from_ref(&newer));
+        assert_eq!(None, notes.get(&older));
+        assert_eq!(Some("after enabling TLS"), notes.get(&newer));
+
+        Notes::default().save(&path).unwrap();
+        assert!(!path.exists());
+
+        let source_file = dir.join("nginx.conf");
+        assert_eq!(vec![path, dir.join("nginx.conf.bak9.tar.notes"), dir.join("nginx.conf.bak9.tar.gz.notes")],
+            manifest_paths(&source_file, &dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
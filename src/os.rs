@@ -1,6 +1,8 @@
-use std::{env, fs, io, path::{Path, PathBuf}};
+use std::{env, fs, io::{self, Read, Seek, SeekFrom, Write}, ops::Range, path::{Path, PathBuf}};
 
-use crate::E_STR;
+use crate::{progress::Progress, PathExt, BAK9, E_STR, TMP};
+
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
 
 fn sanitize_cmd_path(path: &Path) -> &str {
     let path = path.to_str().expect(E_STR);
@@ -11,39 +13,419 @@ fn sanitize_cmd_path(path: &Path) -> &str {
     }
 }
 
+/// Copies `source` to `dest` atomically. The copy is written to a temporary file beside `dest`,
+/// flushed to disk and then renamed into place, so that `dest` is never left partially written.
+///
+/// The copy is never more permissive than `source`, even while it is being written.
 pub fn copy_file(source: &Path, dest: &Path) -> io::Result<()> {
+    // copying into a private file keeps the contents hidden until the permissions are copied too
+    let (tmp_path, _) = create_tmp_beside(dest, create_new_private_file)?;
+
+    let result = copy_file_contents(source, &tmp_path)
+        .and_then(|_| restrict_permissions(source, &tmp_path))
+        .and_then(|_| sync_file(&tmp_path))
+        .and_then(|_| fs::rename(&tmp_path, dest));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Copies the symlink `source` itself to `dest` atomically, rather than the file it points to. A
+/// relative target is kept as is beside `source`, but is made absolute elsewhere so that the copy
+/// still points to the same file.
+pub fn copy_link(source: &Path, dest: &Path) -> io::Result<()> {
+    let mut target = fs::read_link(source)?;
+
+    if target.is_relative() {
+        let parent_dir = |path: &Path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize(),
+            _ => env::current_dir()
+        };
+
+        let source_dir = parent_dir(source)?;
+        if source_dir != parent_dir(dest)? {
+            target = source_dir.join(target);
+        }
+    }
+
+    let (tmp_path, _) = create_tmp_beside(dest, |tmp_path| symlink(&target, tmp_path))?;
+    let result = fs::rename(&tmp_path, dest);
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+/// Copies the contents of `source` into the existing file `dest`, followed by its ownership (where
+/// permitted), timestamps and permissions. Holes in a sparse source are skipped rather than written
+/// out, so that they remain holes in `dest`.
+fn copy_file_contents(source: &Path, dest: &Path) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    let mut writer = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(dest)?;
+
+    let mut progress = Progress::new(source.filename_str().unwrap_or_default(), metadata.len());
+    let mut buffer = Vec::new();
+    let mut len = metadata.len();
+
+    for range in data_ranges(&reader, metadata.len())? {
+        let end = copy_range(&mut reader, &mut writer, range.clone(), &mut buffer, &mut progress)?;
+
+        // truncated while copying
+        if end < range.end {
+            len = end;
+            break
+        }
+    }
+
+    // the holes are whatever is left unwritten, including any at the end
+    writer.set_len(len)?;
+
+    #[cfg(unix)]
+    {
+        // only permitted for root, who may be backing up another user's file
+        use std::os::unix::fs::MetadataExt;
+        let _ = std::os::unix::fs::fchown(&writer, Some(metadata.uid()), Some(metadata.gid()));
+    }
+
+    writer.set_times(fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?))?;
+    writer.set_permissions(metadata.permissions())
+}
+
+/// Copies `range` of `reader` to the same position in `writer`, returning the position that it
+/// was copied up to, which falls short of the end if `reader` was truncated. The kernel copies it
+/// where it can, which may share the data rather than duplicate it, otherwise it's copied through
+/// `buffer`.
+fn copy_range(reader: &mut fs::File, writer: &mut fs::File, range: Range<u64>, buffer: &mut Vec<u8>, progress: &mut Progress) -> io::Result<u64> {
+    let mut position = range.start;
+
     #[cfg(target_os = "linux")]
-    match linux_cp(source, dest) {
-        Ok(_) => return Ok(()),
-        Err(_) => {}, // fallback
+    {
+        use std::os::fd::AsRawFd;
+
+        while position < range.end {
+            let mut offset_in = position as libc::loff_t;
+            let mut offset_out = position as libc::loff_t;
+            let len = (range.end - position).min(COPY_BUFFER_SIZE as u64) as usize;
+
+            // SAFETY: both file descriptors are kept open by `reader` and `writer`, and the offsets
+            // are valid for the duration of the call
+            let n = unsafe {
+                libc::copy_file_range(reader.as_raw_fd(), &mut offset_in, writer.as_raw_fd(), &mut offset_out, len, 0)
+            };
+
+            match n {
+                0 => return Ok(position), // truncated while copying
+                n if n > 0 => {
+                    position += n as u64;
+                    progress.update(position);
+                },
+                _ => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => {},
+                    // unsupported between these files, e.g. across file systems before linux 5.3
+                    e if matches!(e.raw_os_error(), Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP
+                        | libc::EINVAL | libc::EPERM)) => break,
+                    e => return Err(e)
+                }
+            }
+        }
+    }
+
+    if position == range.end {
+        return Ok(position)
+    }
+
+    buffer.resize(COPY_BUFFER_SIZE, 0);
+    reader.seek(SeekFrom::Start(position))?;
+    writer.seek(SeekFrom::Start(position))?;
+
+    while position < range.end {
+        let len = buffer.len().min((range.end - position) as usize);
+        let n = match reader.read(&mut buffer[..len]) {
+            Ok(0) => break, // truncated while copying
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+
+        writer.write_all(&buffer[..n])?;
+        position += n as u64;
+        progress.update(position);
     }
-        
-    fs::copy(source, dest)
-        .map(|_| ())
+
+    Ok(position)
 }
 
-#[cfg(target_os = "linux")]
-fn linux_cp(source: &Path, dest: &Path) -> io::Result<()> {
-    let output = std::process::Command::new("cp")
-        .arg("--preserve")
-        .arg(source)
-        .arg(dest)
-        .output()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-
-    if output.status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(io::ErrorKind::Other, String::from_utf8_lossy(&output.stderr).to_string()))
+/// The byte ranges of `file` that hold data, skipping its holes. A file system that can't report
+/// holes has a single range.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn data_ranges(file: &fs::File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut position = 0;
+
+    while position < len {
+        // SAFETY: lseek only moves the offset of a file descriptor that `file` keeps open
+        let start = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                // the rest of the file is a hole
+                Some(libc::ENXIO) => Ok(ranges),
+                _ if ranges.is_empty() => Ok(std::iter::once(0..len).collect()),
+                _ => Err(e)
+            }
+        }
+
+        // SAFETY: as above
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        ranges.push(start as u64..(end as u64).min(len));
+        position = end as u64;
+    }
+
+    Ok(ranges)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn data_ranges(_file: &fs::File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok(std::iter::once(0..len).collect())
+}
+
+/// Flushes the contents of a file to disk.
+pub fn sync_file(path: &Path) -> io::Result<()> {
+    // windows requires write access to flush, while a read-only copy can't be opened for writing elsewhere
+    fs::OpenOptions::new()
+        .read(true)
+        .write(cfg!(target_os = "windows"))
+        .open(path)?
+        .sync_all()
+}
+
+/// Creates a file, or truncates an existing one, that only the user can access on systems that
+/// support it.
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)
+}
+
+/// Creates a new file that only the user can access, failing if it already exists.
+pub fn create_new_private_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)
+}
+
+/// Creates a temporary file beside `path` with `create`, which must fail if the file already
+/// exists. It is named like `.foo.conf.bak9-1234-0f3a9c2e.tmp`, so that it is never a file of the
+/// user's, and only this temporary file may be deleted by the caller.
+pub fn create_tmp_beside<T>(path: &Path, create: impl Fn(&Path) -> io::Result<T>) -> io::Result<(PathBuf, T)> {
+    let filename = path.file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+        .to_string_lossy();
+
+    loop {
+        let tmp_path = path.with_file_name(format!(".{filename}.{BAK9}-{}-{:08x}.{TMP}",
+            std::process::id(), rand::random::<u32>()));
+
+        match create(&tmp_path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|created| (tmp_path, created))
+        }
     }
 }
 
-pub fn print_diff(source: &Path, file_b: &Path) -> Result<(), crate::Error> {
+/// A file within a temporary directory of its own, which only the user can access on systems that
+/// support it. The directory is deleted along with the file when dropped.
+pub struct PrivateTempFile {
+    // deleted on drop
+    _dir: tempfile::TempDir,
+    path: PathBuf
+}
+
+impl PrivateTempFile {
+    /// Creates a new, empty file named `filename`, which is never an existing file or link.
+    pub fn create(filename: &str) -> io::Result<(Self, fs::File)> {
+        let dir = tempfile::Builder::new()
+            .prefix(crate::BAK9)
+            .tempdir()?;
+        let path = dir.path().join(filename);
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let file = options.open(&path)?;
+        Ok((Self { _dir: dir, path }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Creates a directory and any missing parents, which only the user can access on systems that
+/// support it.
+pub fn create_private_dir_all(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    builder.create(dir)
+}
+
+/// Whether the user may create files in `dir`, which is assumed if it doesn't exist or on systems
+/// that can't tell.
+pub fn is_writable_dir(dir: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+            return false
+        };
+
+        // SAFETY: `path` is a valid nul-terminated string
+        unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 || !dir.exists() }
+    }
+
+    #[cfg(not(unix))]
+    fs::metadata(dir).map(|metadata| !metadata.permissions().readonly()).unwrap_or(true)
+}
+
+/// Whether `a` and `b` are on the same file system, so that files can be renamed between them.
+pub fn is_same_file_system(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => false
+        }
+    }
+
+    // the same drive, at least
+    #[cfg(not(unix))]
+    match (std::path::absolute(a), std::path::absolute(b)) {
+        (Ok(a), Ok(b)) => a.components().next() == b.components().next(),
+        _ => false
+    }
+}
+
+/// Whether `path` can only have been written by the user or root: it's owned by either, and its
+/// directory isn't writable by other users unless the sticky bit keeps them from replacing it.
+/// Always `true` on systems without unix permissions.
+pub fn is_trusted_file(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // SAFETY: geteuid never fails
+        let uid = unsafe { libc::geteuid() };
+        let Ok(metadata) = fs::metadata(path) else {
+            return false
+        };
+        let Some(Ok(dir_metadata)) = path.parent().map(fs::metadata) else {
+            return false
+        };
+
+        let is_owned = |metadata: &fs::Metadata| metadata.uid() == uid || metadata.uid() == 0;
+        let is_shared = dir_metadata.mode() & 0o022 != 0 && dir_metadata.mode() & 0o1000 == 0;
+
+        is_owned(&metadata) && is_owned(&dir_metadata) && !is_shared
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        true
+    }
+}
+
+/// The permission bits that `path` grants beyond those of `source`. Always `0` on systems without
+/// unix permissions.
+pub fn excess_permissions(source: &Path, path: &Path) -> io::Result<u32> {
+    Ok(permission_bits(path)? & !permission_bits(source)?)
+}
+
+/// Removes any permission from `path` that `source` lacks, so that it's never more permissive.
+pub fn restrict_permissions(source: &Path, path: &Path) -> io::Result<()> {
+    if excess_permissions(source, path)? == 0 {
+        return Ok(())
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = permission_bits(path)? & permission_bits(source)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+/// The permission bits of `path`, e.g. `0o644`. Always `0` on systems without unix permissions.
+pub fn permission_bits(path: &Path) -> io::Result<u32> {
+    let metadata = fs::metadata(path)?;
+
+    #[cfg(unix)]
+    return Ok(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777);
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Ok(0)
+    }
+}
+
+pub fn print_diff(source: &Path, file_b: &Path, diff_tool: Option<&str>) -> Result<(), crate::Error> {
     if !crate::diff_files(source, file_b)? {
         println!("No difference");
         return Ok(());
     }
 
+    if let Some(diff_tool) = diff_tool {
+        return run_diff_tool(diff_tool, source, file_b);
+    }
+
     // try `git diff` first. if not available, use a system-specific diff command
     let output = std::process::Command::new("git")
         .arg("diff")
@@ -53,19 +435,16 @@ pub fn print_diff(source: &Path, file_b: &Path) -> Result<(), crate::Error> {
         .arg(sanitize_cmd_path(source))
         .output();
 
-    match output {
-        Ok(output) => {
-            let lines: String = String::from_utf8(output.stdout).expect(E_STR).trim()
-                .lines()
-                .skip(2)
-                .map(|line| format!("{line}\n"))
-                .collect();
+    if let Ok(output) = output {
+        let lines: String = String::from_utf8(output.stdout).expect(E_STR).trim()
+            .lines()
+            .skip(2)
+            .map(|line| format!("{line}\n"))
+            .collect();
 
-            println!("{}", lines.trim());
-            return Ok(());
-        },
-        Err(_) => {} // try system 'diff'
-    }
+        println!("{}", lines.trim());
+        return Ok(());
+    } // else try system 'diff'
 
     if cfg!(any(target_os = "linux", target_os = "macos")) {
         let output = std::process::Command::new("diff")
@@ -93,6 +472,51 @@ pub fn print_diff(source: &Path, file_b: &Path) -> Result<(), crate::Error> {
     }
 }
 
+/// Runs a user specified diff command, e.g. `meld` or `vimdiff -R`, with FILE_B and SOURCE appended.
+fn run_diff_tool(diff_tool: &str, source: &Path, file_b: &Path) -> Result<(), crate::Error> {
+    let mut args = diff_tool.split_whitespace();
+    let program = args.next()
+        .ok_or_else(|| crate::Error::Generic("Empty diff tool".to_string()))?;
+
+    // diff tools commonly exit with a failure status when files differ, so only spawning is checked
+    std::process::Command::new(program)
+        .args(args)
+        .arg(sanitize_cmd_path(file_b))
+        .arg(sanitize_cmd_path(source))
+        .status()
+        .map_err(|e| crate::Error::Generic(format!("Unable to run diff tool {program}: {e}")))?;
+
+    Ok(())
+}
+
+const ENV_VISUAL: &str = "VISUAL";
+const ENV_EDITOR: &str = "EDITOR";
+
+/// Opens `file` in the user's editor, `$VISUAL` or `$EDITOR`, and waits for it to exit.
+pub fn run_editor(file: &Path) -> Result<(), crate::Error> {
+    let editor = env::var(ENV_VISUAL)
+        .or_else(|_| env::var(ENV_EDITOR))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(target_os = "windows") { "notepad" } else { "vi" }.to_string());
+
+    let mut args = editor.split_whitespace();
+    let program = args.next()
+        .ok_or_else(|| crate::Error::Generic("Empty editor".to_string()))?;
+
+    let status = std::process::Command::new(program)
+        .args(args)
+        .arg(sanitize_cmd_path(file))
+        .status()
+        .map_err(|e| crate::Error::Generic(format!("Unable to run editor {program}: {e}")))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(crate::Error::Generic(format!("Editor {program} exited with {status}")))
+    }
+}
+
 /// Retrieves the bak9 data directory if possible, otherwise None.
 pub fn user_app_data_dir(mkdir: bool, app_subdirs: PathBuf) -> io::Result<PathBuf> {
     #[cfg(target_os = "linux")]
@@ -108,13 +532,53 @@ pub fn user_app_data_dir(mkdir: bool, app_subdirs: PathBuf) -> io::Result<PathBu
     if dir.is_dir() {
         dir.canonicalize()
     } else if mkdir {
-        fs::create_dir_all(&dir)?;
+        create_private_dir_all(&dir)?;
         dir.canonicalize()
     } else {
         Err(io::Error::new(io::ErrorKind::NotFound, format!("User app data directory not found: {}", dir.to_str().unwrap())))
     }
 }
 
+/// Retrieves the user's config directory for the app. It may not exist.
+pub fn user_app_config_dir(app_subdirs: PathBuf) -> io::Result<PathBuf> {
+    #[cfg(target_os = "linux")]
+    let os_config_dir = linux_user_config_dir();
+    #[cfg(target_os = "windows")]
+    let os_config_dir = windows_user_config_dir()?;
+    #[cfg(target_os = "macos")]
+    let os_config_dir = macos_user_app_data_dir()?;
+    #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+    return Err(io::Error::new(io::ErrorKind::Other, "Unsupported OS"));
+
+    Ok(os_config_dir.join(app_subdirs))
+}
+
+#[cfg(target_os = "linux")]
+const ENV_XDG_CONFIG_HOME: &str = "XDG_CONFIG_HOME";
+#[cfg(target_os = "linux")]
+const XDG_CONFIG_HOME_DEFAULT: &str = "$HOME/.config";
+
+#[cfg(target_os = "linux")]
+fn linux_user_config_dir() -> PathBuf {
+    let mut var = env::var(ENV_XDG_CONFIG_HOME)
+        .unwrap_or_else(|_| XDG_CONFIG_HOME_DEFAULT.to_string());
+
+    env::vars().for_each(|(k, v)| var = var.replace(&format!("${k}"), &v));
+
+    PathBuf::from(var)
+}
+
+#[cfg(target_os = "windows")]
+const ENV_APP_DATA: &str = "AppData";
+
+#[cfg(target_os = "windows")]
+fn windows_user_config_dir() -> io::Result<PathBuf> {
+    env::var(ENV_APP_DATA)
+        .map(PathBuf::from)
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound,
+            format!("Windows %AppData% not found")))
+}
+
 #[cfg(target_os = "linux")]
 const ENV_XDG_DATA_HOME: &str = "XDG_DATA_HOME";
 #[cfg(target_os = "linux")]
@@ -174,3 +638,57 @@ fn macos_user_app_data_dir() -> io::Result<PathBuf> {
             format!("macOS user app data directory not found: {}", dir.to_str().unwrap())))
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_file() {
+        let dir = crate::test_dir("test_os_copy_file");
+
+        // a sparse file, with data between two holes
+        let source = dir.join("sparse.img");
+        let dest = dir.join("sparse.img.bak");
+        let len = 16 * 1024 * 1024;
+        let mut file = fs::File::create(&source).unwrap();
+        file.set_len(len).unwrap();
+        file.seek(SeekFrom::Start(len / 2)).unwrap();
+        file.write_all(b"DATA").unwrap();
+        drop(file);
+
+        copy_file(&source, &dest).unwrap();
+        assert_eq!(len, fs::metadata(&dest).unwrap().len());
+        assert!(!crate::diff_files(&source, &dest).unwrap());
+        assert_eq!(fs::metadata(&source).unwrap().modified().unwrap(), fs::metadata(&dest).unwrap().modified().unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = |path: &Path| fs::metadata(path).unwrap().blocks() * 512;
+
+            // only if the file system supports holes at all
+            if allocated(&source) < len {
+                assert!(allocated(&dest) < len, "the holes should be preserved");
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_file_tmp() {
+        let dir = crate::test_dir("test_os_copy_file_tmp");
+        let source = dir.join("source.txt");
+        let dest = dir.join("dest.txt");
+        let user_tmp = dir.join("dest.txt.tmp");
+        fs::write(&source, "SOURCE").unwrap();
+        fs::write(&user_tmp, "USER").unwrap();
+
+        // a file of the user's that is named like a temporary file is left alone
+        copy_file(&source, &dest).unwrap();
+        assert_eq!("SOURCE", fs::read_to_string(&dest).unwrap());
+        assert_eq!("USER", fs::read_to_string(&user_tmp).unwrap());
+        assert_eq!(3, fs::read_dir(&dir).unwrap().count());
+    }
+}
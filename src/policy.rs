@@ -0,0 +1,193 @@
+//! Per-directory policies, loaded from `.bak9.toml` files in any ancestor directory of FILE.
+//!
+//! The nearest policy file takes precedence for each setting, falling back to those further up
+//! the tree and then to the user's config. Options given on the command line always take
+//! precedence. Relative destinations and patterns are resolved against the policy's directory.
+//!
+//! Policy files that another user could have written are ignored: those not owned by the user or
+//! root, or within a directory that other users may write to without the sticky bit.
+//!
+//! ```toml
+//! retention = 50
+//! destination = "mirror"      # "sibling", "mirror" or a directory path
+//! naming = "bak"              # "bak" (.bak.N files) or "archive"
+//! compression = "none"        # "none" or "gzip", for archives
+//! include = ["*.conf"]        # only back up matching files
+//! exclude = ["secrets/*"]     # never back up matching files
+//! encrypt = "age1..."         # an age public key, or "passphrase"
+//! ```
+
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::{config::{Compression, Config, Destination, Naming}, crypt::Encryption, os, Error, IoOp};
+
+pub const BAK9_TOML: &str = ".bak9.toml";
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PolicyFile {
+    pub retention: Option<u8>,
+    pub destination: Option<Destination>,
+    pub naming: Option<Naming>,
+    pub compression: Option<Compression>,
+    pub encrypt: Option<Encryption>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>
+}
+
+impl PolicyFile {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::io(IoOp::Read, path, e))?;
+
+        Self::parse(&contents)
+            .map_err(|e| Error::config(path, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let policy: Self = toml::from_str(contents)
+            .map_err(|e| e.message().to_string())?;
+
+        if policy.retention == Some(0) {
+            return Err("retention must be at least 1".to_string())
+        }
+
+        for pattern in policy.include.iter().chain(&policy.exclude) {
+            glob::Pattern::new(pattern)
+                .map_err(|e| format!("invalid pattern {pattern}: {e}"))?;
+        }
+
+        Ok(policy)
+    }
+
+    /// Whether `file` passes the include and exclude patterns, relative to the policy's `dir`.
+    fn allows(&self, dir: &Path, file: &Path) -> bool {
+        let relative_path = file.strip_prefix(dir).unwrap_or(file);
+        let matches = |patterns: &[String]| patterns.iter()
+            .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+            .any(|pattern| pattern.matches_path(relative_path));
+
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+/// The combined policy of every `.bak9.toml` above a file.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// The policy files that were found and the directories they apply to, nearest first
+    pub files: Vec<(PathBuf, PolicyFile)>
+}
+
+impl Policy {
+    /// Loads every trusted policy file in the ancestor directories of `file`.
+    pub fn resolve(file: &Path) -> Result<Self, Error> {
+        let mut files = Vec::new();
+
+        for dir in file.ancestors().skip(1) {
+            let path = dir.join(BAK9_TOML);
+            if path.is_file() && os::is_trusted_file(&path) {
+                files.push((dir.to_path_buf(), PolicyFile::load(&path)?));
+            }
+        }
+
+        Ok(Self { files })
+    }
+
+    /// The settings of the policy as a config, nearest policy first.
+    pub fn config(&self) -> Config {
+        let mut config = Config::default();
+
+        for (dir, policy) in &self.files {
+            config.retention = config.retention.or(policy.retention);
+            config.naming = config.naming.or(policy.naming);
+            config.compression = config.compression.or(policy.compression);
+            config.encrypt = config.encrypt.or_else(|| policy.encrypt.clone());
+            config.destination = config.destination.or_else(|| match &policy.destination {
+                Some(Destination::Dir(dest_dir)) => Some(Destination::Dir(dir.join(dest_dir))),
+                destination => destination.clone()
+            });
+        }
+
+        config
+    }
+
+    /// Whether `file` passes the include and exclude patterns of every policy.
+    pub fn allows(&self, file: &Path) -> bool {
+        self.excluded_by(file).is_none()
+    }
+
+    /// The nearest policy file that excludes `file`, if any.
+    pub fn excluded_by(&self, file: &Path) -> Option<PathBuf> {
+        self.files.iter()
+            .find(|(dir, policy)| !policy.allows(dir, file))
+            .map(|(dir, _)| dir.join(BAK9_TOML))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let policy = Policy { files: vec![
+            (PathBuf::from("/etc/nginx"), PolicyFile::parse("retention = 5").unwrap()),
+            (PathBuf::from("/etc"), PolicyFile::parse(r#"
+                retention = 50
+                destination = "backups"
+                naming = "archive"
+                encrypt = "passphrase"
+            "#).unwrap())
+        ]};
+
+        let config = policy.config();
+        assert_eq!(Some(5), config.retention);
+        assert_eq!(Some(Destination::Dir(PathBuf::from("/etc/backups"))), config.destination);
+        assert_eq!(Some(Naming::Archive), config.naming);
+        assert_eq!(Some(Encryption::Passphrase), config.encrypt);
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = Policy { files: vec![
+            (PathBuf::from("/srv/project"), PolicyFile::parse(r#"
+                include = ["*.conf", "*.toml"]
+                exclude = ["secrets/*"]
+            "#).unwrap())
+        ]};
+
+        assert!(policy.allows(Path::new("/srv/project/app.conf")));
+        assert!(policy.allows(Path::new("/srv/project/sub/app.toml")));
+        assert!(!policy.allows(Path::new("/srv/project/app.log")));
+        assert!(!policy.allows(Path::new("/srv/project/secrets/key.conf")));
+        assert_eq!(Some(PathBuf::from("/srv/project/.bak9.toml")), policy.excluded_by(Path::new("/srv/project/app.log")));
+        assert_eq!(None, policy.excluded_by(Path::new("/srv/project/app.conf")));
+
+        assert!(PolicyFile::parse(r#"include = ["[""]"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_untrusted() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = crate::test_dir("test_policy_resolve_untrusted");
+        let shared_dir = dir.join("shared");
+        fs::create_dir(&shared_dir).unwrap();
+        fs::write(dir.join(BAK9_TOML), "retention = 5").unwrap();
+        fs::write(shared_dir.join(BAK9_TOML), "retention = 50").unwrap();
+        let file = shared_dir.join("app.conf");
+
+        // anyone could have replaced the policy in a world-writable directory
+        fs::set_permissions(&shared_dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(Some(5), Policy::resolve(&file).unwrap().config().retention);
+
+        // unless the sticky bit keeps them from replacing files that aren't theirs
+        fs::set_permissions(&shared_dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(Some(50), Policy::resolve(&file).unwrap().config().retention);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! A progress bar for copies of large files, drawn on stdout when it is a terminal.
+
+use std::{io::{self, IsTerminal, Read, Write}, sync::atomic::{AtomicBool, Ordering}};
+
+/// Files smaller than this are copied without a progress bar.
+pub const THRESHOLD: u64 = 64 * 1024 * 1024;
+
+const WIDTH: usize = 30;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables progress bars for the rest of the process, if `enabled` is set and stdout is a terminal.
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled && io::stdout().is_terminal(), Ordering::Relaxed);
+}
+
+/// The progress of copying `total` bytes. Nothing is drawn unless progress bars are enabled and
+/// the total is at least [THRESHOLD].
+pub struct Progress {
+    label: String,
+    total: u64,
+    visible: bool,
+    percent: Option<u64>
+}
+
+impl Progress {
+    pub fn new(label: &str, total: u64) -> Self {
+        Self {
+            label: label.to_string(),
+            total,
+            visible: total >= THRESHOLD && ENABLED.load(Ordering::Relaxed),
+            percent: None
+        }
+    }
+
+    /// Redraws the bar after `done` bytes, if its percentage has changed.
+    pub fn update(&mut self, done: u64) {
+        if !self.visible {
+            return
+        }
+
+        let percent = percent(done, self.total);
+        if self.percent == Some(percent) {
+            return
+        }
+
+        self.percent = Some(percent);
+        print!("\r{}", render(&self.label, done, self.total));
+        let _ = io::stdout().flush();
+    }
+}
+
+impl Drop for Progress {
+    /// Clears the bar.
+    fn drop(&mut self) {
+        if self.visible && self.percent.is_some() {
+            print!("\r\x1b[2K");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+/// Reports the progress of everything read through it.
+pub struct ProgressReader<R> {
+    reader: R,
+    progress: Progress,
+    done: u64
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(reader: R, progress: Progress) -> Self {
+        Self { reader, progress, done: 0 }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.done += n as u64;
+        self.progress.update(self.done);
+        Ok(n)
+    }
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    if total == 0 { 100 } else { (done.min(total) as u128 * 100 / total as u128) as u64 }
+}
+
+/// Renders a bar, e.g. `disk.img [#######        ]  25%  1.0 GiB / 4.0 GiB`.
+fn render(label: &str, done: u64, total: u64) -> String {
+    let percent = percent(done, total);
+    let filled = percent as usize * WIDTH / 100;
+
+    format!("{label} [{}{}] {percent:>3}%  {} / {}",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        crate::format_size(done.min(total)),
+        crate::format_size(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let gib = 1024 * 1024 * 1024;
+        assert_eq!("disk.img [#######                       ]  25%  1.0 GiB / 4.0 GiB", render("disk.img", gib, 4 * gib));
+        assert_eq!("disk.img [##############################] 100%  4.0 GiB / 4.0 GiB", render("disk.img", 5 * gib, 4 * gib));
+        assert_eq!(100, percent(0, 0));
+    }
+}
@@ -0,0 +1,973 @@
+//! Backup stores.
+//!
+//! A [BackupStore] owns every backup of a single source file at a single location. The CLI only
+//! speaks to stores, so new locations can be added without touching command handling.
+
+mod journal;
+mod tar;
+
+use std::{fs, io, ops::Deref, path::{Path, PathBuf}, time::SystemTime};
+use crate::{crypt::{self, Encryption}, os, Error, IoOp, PathExt, BAK, BAK9, BAK_0, BAK_1, BAK_DOT, E_FILENAME, TMP};
+
+use self::journal::Journal;
+pub use self::tar::{Archive, Member, TarStore};
+
+/// A location that holds the rotating backups of one source file.
+pub trait BackupStore {
+    /// The source file whose backups are held by this store.
+    fn source_file(&self) -> &Path;
+
+    /// The directory that holds the backups.
+    fn location(&self) -> PathBuf;
+
+    /// Lists all backups, most recent first.
+    fn list(&self) -> Result<Vec<PathBuf>, Error>;
+
+    /// Retrieves the backup at `index`, where `0` is the most recent.
+    fn get(&self, index: u8) -> Result<BackupFile, Error> {
+        self.list()?
+            .into_iter()
+            .nth(index as usize)
+            .map(BackupFile::from)
+            .ok_or_else(|| Error::index(self.source_file(), index))
+    }
+
+    /// Streams the contents of the backup at `index` to `read`, without retrieving it as a file.
+    fn read(&self, index: u8, read: &mut dyn FnMut(&mut dyn io::Read) -> Result<(), Error>) -> Result<(), Error> {
+        let bak_filepath = self.get(index)?;
+        let mut file = fs::File::open(&bak_filepath)
+            .map_err(|e| Error::io(IoOp::Read, &bak_filepath, e))?;
+        read(&mut file)
+    }
+
+    /// The modification time of each backup, most recent first, which it keeps as it is rotated.
+    fn timestamps(&self) -> Result<Vec<Option<SystemTime>>, Error> {
+        Ok(self.list()?
+            .into_iter()
+            .map(|bak_filepath| fs::symlink_metadata(bak_filepath)
+                .and_then(|metadata| metadata.modified())
+                .ok())
+            .collect())
+    }
+
+    /// The size of each backup in bytes, most recent first.
+    fn sizes(&self) -> Result<Vec<u64>, Error> {
+        Ok(self.list()?
+            .into_iter()
+            .map(|bak_filepath| fs::symlink_metadata(bak_filepath)
+                .map(|metadata| metadata.len())
+                .unwrap_or_default())
+            .collect())
+    }
+
+    /// Prunes and shifts existing backups to make room for a new one, keeping at most `max`.
+    /// Returns the path that the new backup should be written to, or `None` if the source file is
+    /// unchanged since the last backup.
+    fn rotate(&self, max: u8) -> Result<Option<PathBuf>, Error>;
+
+    /// Creates a new backup of the source file, keeping at most `max`.
+    /// Returns the path of the new backup, or `None` if the source file is unchanged.
+    fn put(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        let bak_filepath = match self.rotate(max)? {
+            Some(filepath) => filepath,
+            None => return Ok(None)
+        };
+
+        os::copy_file(self.source_file(), &bak_filepath)
+            .map_err(|e| Error::copy(self.source_file(), &bak_filepath, e))?;
+
+        Ok(Some(bak_filepath))
+    }
+
+    /// Overwrites `dest` with the backup at `index`.
+    fn restore(&self, index: u8, dest: &Path) -> Result<(), Error> {
+        let bak_filepath = self.get(index)?;
+        os::copy_file(&bak_filepath, dest)
+            .map_err(|e| Error::copy(&bak_filepath, dest, e))
+    }
+
+    /// The steps that `put` would perform, without performing them.
+    fn plan_put(&self, max: u8) -> Result<Vec<Step>, Error>;
+
+    /// The files that hold the backups, which are the backups themselves unless they are archived.
+    fn files(&self) -> Result<Vec<PathBuf>, Error> {
+        self.list()
+    }
+
+    /// The steps that `delete` would perform, without performing them.
+    fn plan_delete(&self) -> Result<Vec<Step>, Error> {
+        Ok(self.files()?
+            .into_iter()
+            .map(Step::Delete)
+            .collect())
+    }
+
+    /// Moves every backup into the user's trash, rather than deleting them.
+    fn trash(&self) -> Result<(), Error> {
+        for step in self.plan_delete()? {
+            if let Step::Delete(path) = step {
+                crate::trash::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the most recent backup, shifting the older backups back into its place. This undoes
+    /// a `put`, other than any backup that it pruned.
+    fn discard(&self) -> Result<(), Error>;
+
+    /// Deletes all backups.
+    fn delete(&self) -> Result<(), Error>;
+}
+
+/// A backup retrieved from a store: either the backup itself, or a private copy extracted from an
+/// archive, which is deleted when dropped.
+pub struct BackupFile {
+    path: PathBuf,
+    _extracted: Option<os::PrivateTempFile>
+}
+
+impl BackupFile {
+    pub fn extracted(extracted: os::PrivateTempFile) -> Self {
+        Self { path: extracted.path().to_path_buf(), _extracted: Some(extracted) }
+    }
+}
+
+impl From<PathBuf> for BackupFile {
+    fn from(path: PathBuf) -> Self {
+        Self { path, _extracted: None }
+    }
+}
+
+impl Deref for BackupFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for BackupFile {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A single filesystem operation performed by a store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Rename(PathBuf, PathBuf),
+    Delete(PathBuf),
+    Copy(PathBuf, PathBuf)
+}
+
+/// The store for backups held directly within `dir`, either as `.bak.N` files or in an archive.
+pub fn dir_store(source_file: &Path, dir: &Path, archive: Option<Archive>) -> Box<dyn BackupStore> {
+    match archive {
+        Some(archive) => Box::new(TarStore::new(source_file, dir, archive)),
+        None => Box::new(SiblingDirStore::new(source_file, dir))
+    }
+}
+
+/// Stores backups in a plain directory, alongside the source file by default.
+pub struct SiblingDirStore {
+    source_file: PathBuf,
+    dir: PathBuf,
+    trash: bool,
+    encryption: Option<Encryption>,
+    copy_links: bool
+}
+
+impl SiblingDirStore {
+    pub fn new(source_file: &Path, dir: &Path) -> Self {
+        Self {
+            source_file: source_file.to_path_buf(),
+            dir: dir.to_path_buf(),
+            trash: false,
+            encryption: None,
+            copy_links: false
+        }
+    }
+
+    /// Moves pruned backups into the user's trash, rather than deleting them.
+    pub fn with_trash(mut self, trash: bool) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    /// Encrypts new backups.
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Backs up a symlinked source file as a link, rather than the file it points to.
+    pub fn with_copy_links(mut self, copy_links: bool) -> Self {
+        self.copy_links = copy_links;
+        self
+    }
+}
+
+impl SiblingDirStore {
+    /// Recovers from any interrupted rotation, and then plans the next one.
+    fn plan(&self, max: u8) -> Result<Option<Journal>, Error> {
+        journal::recover(&self.source_file, &self.dir)?;
+        remove_stale_tmp_files(&self.source_file, &self.dir)?;
+        Ok(determine_destination(&self.source_file, &self.dir, max)?
+            .map(|journal| journal.with_trash(self.trash)))
+    }
+}
+
+impl BackupStore for SiblingDirStore {
+    fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    fn location(&self) -> PathBuf {
+        self.dir.clone()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>, Error> {
+        list_baks(&self.source_file, &self.dir)
+    }
+
+    /// Rotates the backups and then writes an empty placeholder for the new one, so that the
+    /// rotation is never left with a gap.
+    fn rotate(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        match self.plan(max)? {
+            Some(journal) => journal.run(|tmp_path| fs::File::create(tmp_path)
+                .map(|_| ())
+                .map_err(|e| Error::io(IoOp::Create, tmp_path, e))),
+            None => Ok(None)
+        }
+    }
+
+    fn put(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        let journal = match self.plan(max)? {
+            Some(journal) => journal,
+            None => return Ok(None)
+        };
+
+        let is_link = self.copy_links && self.source_file.is_symlink();
+        if is_link && self.encryption.is_some() {
+            return Err(Error::Generic(format!("Unable to encrypt a backup of the symlink {}",
+                self.source_file.to_str().expect(crate::E_STR))))
+        }
+
+        let hash = match &self.encryption {
+            Some(_) => Some(crypt::keyed_hash(&self.source_file)?),
+            None => None
+        };
+
+        let bak_filepath = journal.with_hash(hash).run(|tmp_path| match &self.encryption {
+            _ if is_link => os::copy_link(&self.source_file, tmp_path)
+                .map_err(|e| Error::copy(&self.source_file, tmp_path, e)),
+            Some(encryption) => encryption.encrypt_file(&self.source_file, tmp_path),
+            None => os::copy_file(&self.source_file, tmp_path)
+                .map_err(|e| Error::copy(&self.source_file, tmp_path, e))
+        })?;
+
+        Ok(bak_filepath)
+    }
+
+    /// A backup of a symlink is restored as a link.
+    fn restore(&self, index: u8, dest: &Path) -> Result<(), Error> {
+        let bak_filepath = self.get(index)?;
+        let result = if bak_filepath.is_symlink() {
+            os::copy_link(&bak_filepath, dest)
+        } else {
+            os::copy_file(&bak_filepath, dest)
+        };
+
+        result.map_err(|e| Error::copy(&bak_filepath, dest, e))
+    }
+
+    fn plan_put(&self, max: u8) -> Result<Vec<Step>, Error> {
+        Ok(determine_destination(&self.source_file, &self.dir, max)?
+            .map(|journal| journal.plan(&self.source_file))
+            .unwrap_or_default())
+    }
+
+    fn discard(&self) -> Result<(), Error> {
+        journal::recover(&self.source_file, &self.dir)?;
+        unshift_bak_files(&self.source_file, &self.dir)?
+            .run(|_| Ok(()))
+            .map(|_| ())
+    }
+
+    fn trash(&self) -> Result<(), Error> {
+        for bak_filepath in list_baks(&self.source_file, &self.dir)? {
+            crate::trash::remove_file(&bak_filepath)?;
+        }
+
+        crypt::remove_hash(&self.source_file, &self.dir)
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        wipe(&self.source_file, &self.dir)
+    }
+}
+
+/// Stores backups in the user's app data directory, under a subdirectory that mirrors the
+/// absolute path of the source file's directory.
+pub struct MirrorStore {
+    source_file: PathBuf,
+    app_data_dir: PathBuf,
+    archive: Option<Archive>,
+    trash: bool,
+    encryption: Option<Encryption>,
+    copy_links: bool
+}
+
+impl MirrorStore {
+    pub fn new(source_file: &Path, app_data_dir: &Path) -> Self {
+        Self {
+            source_file: source_file.to_path_buf(),
+            app_data_dir: app_data_dir.to_path_buf(),
+            archive: None,
+            trash: false,
+            encryption: None,
+            copy_links: false
+        }
+    }
+
+    /// Keeps the mirrored backups in an archive rather than as `.bak.N` files.
+    pub fn with_archive(mut self, archive: Option<Archive>) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    /// Moves pruned `.bak.N` files into the user's trash, rather than deleting them.
+    pub fn with_trash(mut self, trash: bool) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    /// Encrypts new backups, so that the mirror never holds a plaintext copy.
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Backs up a symlinked source file as a link, rather than the file it points to.
+    pub fn with_copy_links(mut self, copy_links: bool) -> Self {
+        self.copy_links = copy_links;
+        self
+    }
+
+    pub fn app_data_dir(&self) -> &Path {
+        &self.app_data_dir
+    }
+
+    /// The store within the mirror directory, if it exists or `mkdir` is set.
+    fn mirrored(&self, mkdir: bool) -> Result<Option<Box<dyn BackupStore>>, Error> {
+        match crate::mirror_dir(&self.app_data_dir, &self.source_file, mkdir) {
+            Ok(mirror_dir) => Ok(Some(match self.archive {
+                Some(archive) => Box::new(TarStore::new(&self.source_file, &mirror_dir, archive)
+                    .with_encryption(self.encryption.clone())),
+                None => Box::new(SiblingDirStore::new(&self.source_file, &mirror_dir)
+                    .with_trash(self.trash)
+                    .with_encryption(self.encryption.clone())
+                    .with_copy_links(self.copy_links))
+            })),
+            Err(e) if mkdir => Err(e),
+            Err(_) => Ok(None)
+        }
+    }
+}
+
+impl BackupStore for MirrorStore {
+    fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    fn location(&self) -> PathBuf {
+        let mirror_dir = crate::determine_mirror_dir(&self.app_data_dir, &self.source_file);
+        dir_store(&self.source_file, &mirror_dir, self.archive).location()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.list(),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn get(&self, index: u8) -> Result<BackupFile, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.get(index),
+            None => Err(Error::index(&self.source_file, index))
+        }
+    }
+
+    fn read(&self, index: u8, read: &mut dyn FnMut(&mut dyn io::Read) -> Result<(), Error>) -> Result<(), Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.read(index, read),
+            None => Err(Error::index(&self.source_file, index))
+        }
+    }
+
+    fn restore(&self, index: u8, dest: &Path) -> Result<(), Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.restore(index, dest),
+            None => Err(Error::index(&self.source_file, index))
+        }
+    }
+
+    fn timestamps(&self) -> Result<Vec<Option<SystemTime>>, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.timestamps(),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn sizes(&self) -> Result<Vec<u64>, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.sizes(),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn rotate(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        self.mirrored(true)?.expect("Expected mirror directory").rotate(max)
+    }
+
+    fn put(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        self.mirrored(true)?.expect("Expected mirror directory").put(max)
+    }
+
+    fn plan_put(&self, max: u8) -> Result<Vec<Step>, Error> {
+        let mirror_dir = crate::determine_mirror_dir(&self.app_data_dir, &self.source_file);
+        dir_store(&self.source_file, &mirror_dir, self.archive).plan_put(max)
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.files(),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn plan_delete(&self) -> Result<Vec<Step>, Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.plan_delete(),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn discard(&self) -> Result<(), Error> {
+        match self.mirrored(false)? {
+            Some(store) => store.discard(),
+            None => Ok(())
+        }
+    }
+
+    fn trash(&self) -> Result<(), Error> {
+        if let Some(store) = self.mirrored(false)? {
+            store.trash()?;
+            let mirror_dir = crate::mirror_dir(&self.app_data_dir, &self.source_file, false)?;
+            crate::remove_app_data_subdir(&self.app_data_dir, &mirror_dir)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        if let Some(store) = self.mirrored(false)? {
+            store.delete()?;
+            let mirror_dir = crate::mirror_dir(&self.app_data_dir, &self.source_file, false)?;
+            crate::remove_app_data_subdir(&self.app_data_dir, &mirror_dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes to a primary store, falling back to a secondary store if the primary denies permission.
+pub struct FallbackStore {
+    primary: Box<dyn BackupStore>,
+    fallback: Box<dyn BackupStore>
+}
+
+impl FallbackStore {
+    pub fn new(primary: Box<dyn BackupStore>, fallback: Box<dyn BackupStore>) -> Self {
+        Self { primary, fallback }
+    }
+
+    pub fn primary(&self) -> &dyn BackupStore {
+        self.primary.as_ref()
+    }
+
+    pub fn fallback(&self) -> &dyn BackupStore {
+        self.fallback.as_ref()
+    }
+
+    /// Whether the backup at `bak_filepath` was written to the fallback store.
+    pub fn is_fallback(&self, bak_filepath: &Path) -> bool {
+        bak_filepath.parent() != Some(self.primary.location().as_path())
+    }
+
+    /// The store that holds the backup at `index` as listed, along with its index within it. The
+    /// primary's backups are listed before the fallback's.
+    fn locate(&self, index: u8) -> Result<(&dyn BackupStore, u8), Error> {
+        let primary_len = self.primary.list()?.len();
+        match usize::from(index).checked_sub(primary_len) {
+            None => Ok((self.primary.as_ref(), index)),
+            Some(fallback_index) if fallback_index < self.fallback.list()?.len() =>
+                Ok((self.fallback.as_ref(), fallback_index as u8)),
+            Some(_) => Err(Error::index(self.source_file(), index))
+        }
+    }
+}
+
+impl BackupStore for FallbackStore {
+    fn source_file(&self) -> &Path {
+        self.primary.source_file()
+    }
+
+    fn location(&self) -> PathBuf {
+        self.primary.location()
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut bak_filepaths = self.primary.list()?;
+        bak_filepaths.extend(self.fallback.list()?);
+        Ok(bak_filepaths)
+    }
+
+    fn get(&self, index: u8) -> Result<BackupFile, Error> {
+        let (store, index) = self.locate(index)?;
+        store.get(index)
+    }
+
+    fn read(&self, index: u8, read: &mut dyn FnMut(&mut dyn io::Read) -> Result<(), Error>) -> Result<(), Error> {
+        let (store, index) = self.locate(index)?;
+        store.read(index, read)
+    }
+
+    fn restore(&self, index: u8, dest: &Path) -> Result<(), Error> {
+        let (store, index) = self.locate(index)?;
+        store.restore(index, dest)
+    }
+
+    fn timestamps(&self) -> Result<Vec<Option<SystemTime>>, Error> {
+        let mut timestamps = self.primary.timestamps()?;
+        timestamps.extend(self.fallback.timestamps()?);
+        Ok(timestamps)
+    }
+
+    fn sizes(&self) -> Result<Vec<u64>, Error> {
+        let mut sizes = self.primary.sizes()?;
+        sizes.extend(self.fallback.sizes()?);
+        Ok(sizes)
+    }
+
+    fn rotate(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        self.primary.rotate(max)
+    }
+
+    fn put(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        match self.primary.put(max) {
+            Err(e) if e.is_permission_denied() => self.fallback.put(max)
+                .map_err(|_| e),
+            result => result
+        }
+    }
+
+    fn plan_put(&self, max: u8) -> Result<Vec<Step>, Error> {
+        // an archive is rewritten by renaming a temporary file into its directory
+        let location = self.primary.location();
+        let dir = match crate::mirror::archive_of(&location) {
+            Some(_) => location.parent().unwrap_or(&location),
+            None => &location
+        };
+
+        if os::is_writable_dir(dir) {
+            self.primary.plan_put(max)
+        } else {
+            self.fallback.plan_put(max)
+        }
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut files = self.primary.files()?;
+        files.extend(self.fallback.files()?);
+        Ok(files)
+    }
+
+    fn plan_delete(&self) -> Result<Vec<Step>, Error> {
+        let mut steps = self.primary.plan_delete()?;
+        steps.extend(self.fallback.plan_delete()?);
+        Ok(steps)
+    }
+
+    fn discard(&self) -> Result<(), Error> {
+        if self.primary.list()?.is_empty() {
+            self.fallback.discard()
+        } else {
+            self.primary.discard()
+        }
+    }
+
+    fn trash(&self) -> Result<(), Error> {
+        self.primary.trash()?;
+        self.fallback.trash()
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        self.primary.delete()?;
+        self.fallback.delete()
+    }
+}
+
+/// Moves every backup of `source_file` within `dir` to `new_dir`, renaming them after
+/// `new_source_file`, along with the hash of the latest backup and the notes on them. Returns the
+/// number of backup files moved. Nothing is moved if any of the destinations already exist.
+///
+/// The moves are journaled beside the new backups, so that an interrupted move is completed or
+/// rolled back by the next backup of `new_source_file`, or by moving it again.
+pub fn relocate(source_file: &Path, dir: &Path, new_source_file: &Path, new_dir: &Path) -> Result<usize, Error> {
+    journal::recover(new_source_file, new_dir)?;
+
+    let steps = plan_relocate(source_file, dir, new_source_file, new_dir)?;
+    if steps.is_empty() {
+        return Ok(0)
+    }
+
+    let mut journal = Journal::new(new_source_file, new_dir);
+    let mut moved = 0;
+
+    for step in &steps {
+        let Step::Rename(path, new_path) = step else { continue };
+        journal.relocate(path, new_path);
+
+        if path.filename_str().and_then(source_filename).is_some() {
+            moved += 1;
+        }
+    }
+
+    journal.run(|_| Ok(()))?;
+    Ok(moved)
+}
+
+/// The steps that [relocate] would perform, failing if any of the destinations already exist.
+pub fn plan_relocate(source_file: &Path, dir: &Path, new_source_file: &Path, new_dir: &Path) -> Result<Vec<Step>, Error> {
+    let old_filename = source_file.filename_str().expect(E_FILENAME);
+    let new_filename = new_source_file.filename_str().expect(E_FILENAME);
+
+    if !dir.is_dir() || (dir == new_dir && old_filename == new_filename) {
+        return Ok(Vec::new())
+    }
+
+    let entries = dir.read_dir()
+        .map_err(|e| Error::io(IoOp::Read, dir, e))?;
+
+    let mut moves: Vec<(PathBuf, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_bak_file(path))
+        .filter_map(|path| {
+            let filename = path.filename_str()?;
+            if source_filename(filename)? != old_filename {
+                return None
+            }
+
+            let suffix = filename.strip_prefix(old_filename)?;
+            let new_path = new_dir.join(format!("{new_filename}{suffix}"));
+            Some((path, new_path))
+        })
+        .collect();
+
+    // the files that accompany the backups follow them
+    let sidecars = |source_file: &Path, dir: &Path| crypt::hash_paths(source_file, dir).into_iter()
+        .chain(crate::notes::manifest_paths(source_file, dir));
+    moves.extend(sidecars(source_file, dir)
+        .zip(sidecars(new_source_file, new_dir))
+        .filter(|(path, _)| exists(path)));
+
+    if let Some((_, new_path)) = moves.iter().find(|(_, new_path)| exists(new_path)) {
+        return Err(Error::Generic(format!("Backup already exists: {}", new_path.to_str().expect(crate::E_STR))))
+    }
+
+    Ok(moves.into_iter()
+        .map(|(path, new_path)| Step::Rename(path, new_path))
+        .collect())
+}
+
+/// Parses the source filename out of a backup's filename: `.bak`, `.bak.N` or a `.bak9` archive.
+pub fn source_filename(bak_filename: &str) -> Option<&str> {
+    if let Some(source_filename) = bak_filename.strip_suffix(&format!(".{BAK}")) {
+        return Some(source_filename)
+    }
+
+    for archive in [Archive::Tar, Archive::TarGz] {
+        if let Some(source_filename) = bak_filename.strip_suffix(&format!(".{BAK9}.{}", archive.extension())) {
+            return Some(source_filename)
+        }
+    }
+
+    let (source_filename, n) = bak_filename.rsplit_once(&format!(".{BAK_DOT}"))?;
+    n.parse::<u32>().ok()
+        .map(|_| source_filename)
+}
+
+/// Parses the `N` of a `.bak.N` filename.
+pub(crate) fn bak_n_index(source_file: &Path, bak_filepath: &Path) -> Option<u32> {
+    let bak_n_file_pattern = source_file
+        .append_extension(BAK_DOT)
+        .filename_string().expect(E_FILENAME);
+
+    bak_filepath.filename_str()?
+        .strip_prefix(&bak_n_file_pattern)?
+        .parse::<u32>()
+        .ok()
+}
+
+/// Retrieves the `.bak` file, or all `.bak.N` files, in the directory. Most recent first.
+pub(crate) fn list_baks(source_file: &Path, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new())
+    }
+
+    let mut bak_filepaths = Vec::new();
+
+    let bak_file = dir.join(source_file.filename_str().expect(E_FILENAME))
+        .append_extension(BAK);
+
+    if exists(&bak_file) {
+        bak_filepaths.push(bak_file);
+    }
+
+    bak_filepaths.extend(list_bak_n_files(source_file, dir)?);
+    Ok(bak_filepaths)
+}
+
+/// Retrieves a list of all `.bak.N` files in the directory, ordered by `N`.
+pub(crate) fn list_bak_n_files(file: &Path, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = dir.read_dir()
+        .map_err(|e| Error::io(IoOp::Read, dir, e))?;
+    let mut paths: Vec<(u32, PathBuf)> = dir
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_bak_file(path))
+        .filter_map(|filepath| bak_n_index(file, &filepath).map(|n| (n, filepath)))
+        .collect();
+
+    paths.sort();
+
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Deletes the temporary files of any backup of `source_file` that was interrupted before being
+/// renamed into place, e.g. `foo.conf.bak.0.tmp` or `foo.conf.bak.0.tmp.tmp`.
+pub(crate) fn remove_stale_tmp_files(source_file: &Path, dir: &Path) -> Result<(), Error> {
+    if !dir.is_dir() {
+        return Ok(())
+    }
+
+    let source_filename = source_file.filename_str().expect(E_FILENAME);
+    let entries = dir.read_dir()
+        .map_err(|e| Error::io(IoOp::Read, dir, e))?;
+
+    for tmp_filepath in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        let is_stale = is_bak_file(&tmp_filepath) && tmp_filepath.filename_str()
+            .filter(|filename| filename.ends_with(&format!(".{TMP}")))
+            .map(|filename| filename.trim_end_matches(&format!(".{TMP}")))
+            .and_then(self::source_filename)
+            .is_some_and(|filename| filename == source_filename);
+
+        if is_stale {
+            fs::remove_file(&tmp_filepath)
+                .map_err(|e| Error::io(IoOp::Delete, &tmp_filepath, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Performs a wipe of all `.bak` files in the directory.
+pub(crate) fn wipe(source_file: &Path, dest_dir: &Path) -> Result<(), Error> {
+    for bak_filepath in list_baks(source_file, dest_dir)? {
+        fs::remove_file(&bak_filepath)
+            .map_err(|e| Error::io(IoOp::Delete, &bak_filepath, e))?;
+    }
+
+    crypt::remove_hash(source_file, dest_dir)
+}
+
+/// Plans the rotation that makes room for a new backup of `source_file` in `dest_dir`, keeping at
+/// most `max`. Returns `None` if the source file is unchanged since the last backup. Nothing is
+/// changed until the plan is run.
+pub(crate) fn determine_destination(source_file: &Path, dest_dir: &Path, max: u8) -> Result<Option<Journal>, Error> {
+    let source_filename = source_file.filename_str().expect(E_FILENAME);
+    let last_bak = find_last_bak(source_file, dest_dir);
+    let mut journal = Journal::new(source_file, dest_dir);
+
+    if let Some(last_bak_filepath) = &last_bak {
+        let is_unchanged = if last_bak_filepath.is_symlink() {
+            // a backup of a symlink is unchanged only while the source is a link to the same target
+            source_file.is_symlink() && fs::read_link(source_file).ok() == fs::read_link(last_bak_filepath).ok()
+        } else if crypt::is_encrypted(source_file, last_bak_filepath)? {
+            crypt::matches_hash(source_file, last_bak_filepath)?
+        } else {
+            !crate::diff_files(last_bak_filepath, source_file)?
+        };
+
+        if is_unchanged {
+            return Ok(None)
+        }
+    }
+
+    let bak_filepath = if let Some(last_bak_filepath) = &last_bak {
+        if max == 1 {
+            for bak_filepath in list_baks(source_file, dest_dir)? {
+                journal.prune(&bak_filepath);
+            }
+
+            dest_dir
+                .join(source_filename)
+                .append_extension(BAK)
+        } else if last_bak_filepath.extension().expect("Expected .bak") == BAK {
+            shift_bak_files(source_file, dest_dir, max, &mut journal)?;
+
+            let bak1_filepath = dest_dir
+                .join(source_filename)
+                .append_extension(BAK_1);
+
+            journal.rename(last_bak_filepath, &bak1_filepath);
+
+            dest_dir
+                .join(source_filename)
+                .append_extension(BAK_0)
+        } else {
+            shift_bak_files(source_file, dest_dir, max, &mut journal)?;
+
+            dest_dir
+                .join(source_filename)
+                .append_extension(BAK_0)
+        }
+    } else {
+        dest_dir
+            .join(source_filename)
+            .append_extension(BAK)
+    };
+
+    journal.write(&bak_filepath);
+    Ok(Some(journal))
+}
+
+/// Plans to increment the filename extension of all `.bak.N` files in the directory.
+fn shift_bak_files(file: &Path, dir: &Path, num: u8, journal: &mut Journal) -> Result<(), Error> {
+    let mut bak_filepaths = list_bak_n_files(file, dir)?;
+
+    // prune all excess backups
+    if bak_filepaths.len() >= num as usize {
+        let prune_amount = bak_filepaths.len() - num as usize + 1;
+        for _i in 0..prune_amount {
+            let bak_filepath = bak_filepaths.pop().expect("Expected array value");
+            journal.prune(&bak_filepath);
+        }
+    }
+
+    // shift each up by 1
+    let source_filename = file.filename_string().expect(E_FILENAME);
+    for bak_filepath in bak_filepaths.into_iter().rev() {
+        let n = bak_n_index(file, &bak_filepath).expect("Expected numeric extension");
+        let bak_next_filepath = dir.join(&source_filename)
+            .append_extension(BAK)
+            .append_extension((n + 1).to_string().as_str());
+        journal.rename(&bak_filepath, &bak_next_filepath);
+    }
+
+    Ok(())
+}
+
+/// Plans to delete the `.bak` or `.bak.0` file, decrementing the filename extension of all other
+/// `.bak.N` files in the directory. A lone remaining `.bak.0` file becomes the `.bak` file again.
+fn unshift_bak_files(file: &Path, dir: &Path) -> Result<Journal, Error> {
+    let source_filename = file.filename_string().expect(E_FILENAME);
+    let bak_filepath = dir.join(&source_filename)
+        .append_extension(BAK);
+    let mut journal = Journal::new(file, dir);
+
+    if exists(&bak_filepath) {
+        journal.prune(&bak_filepath);
+        return Ok(journal)
+    }
+
+    let mut bak_filepaths = list_bak_n_files(file, dir)?.into_iter();
+    if let Some(last_bak_filepath) = bak_filepaths.next() {
+        journal.prune(&last_bak_filepath);
+    }
+
+    let bak_filepaths: Vec<PathBuf> = bak_filepaths.collect();
+    let remaining = bak_filepaths.len();
+
+    for bak_filepath in bak_filepaths {
+        let n = bak_n_index(file, &bak_filepath).expect("Expected numeric extension");
+        let bak_prev_filepath = if remaining == 1 {
+            dir.join(&source_filename)
+                .append_extension(BAK)
+        } else {
+            dir.join(&source_filename)
+                .append_extension(BAK)
+                .append_extension((n - 1).to_string().as_str())
+        };
+
+        journal.rename(&bak_filepath, &bak_prev_filepath);
+    }
+
+    Ok(journal)
+}
+
+/// Whether `path` exists, without following it if it's a symlink.
+pub(crate) fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Whether `path` is a file or a symlink, which may be a backup of a symlink.
+fn is_bak_file(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file() || metadata.is_symlink())
+}
+
+/// Returns either a `.bak` or `.bak.0` file if it exists.
+fn find_last_bak(file: &Path, dir: &Path) -> Option<PathBuf> {
+    let bak_file = dir.join(file.filename_str().expect(E_FILENAME))
+        .append_extension(BAK);
+    if exists(&bak_file) {
+        Some(bak_file)
+    } else {
+        let bak0_file = dir.join(file.filename_str().expect(E_FILENAME))
+            .append_extension(BAK_0);
+        if exists(&bak0_file) {
+            Some(bak0_file)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_source_filename() {
+        assert_eq!(Some("source.txt"), source_filename("source.txt.bak"));
+        assert_eq!(Some("source.txt"), source_filename("source.txt.bak.3"));
+        assert_eq!(Some("source.txt"), source_filename("source.txt.bak9.tar"));
+        assert_eq!(Some("source.txt"), source_filename("source.txt.bak9.tar.gz"));
+        assert_eq!(None, source_filename("source.txt.bak.x"));
+        assert_eq!(None, source_filename("source.txt"));
+    }
+
+    #[test]
+    fn test_bak_n_index() {
+        let source_file = Path::new("/tmp/source.txt");
+        assert_eq!(Some(0), bak_n_index(source_file, Path::new("/tmp/source.txt.bak.0")));
+        assert_eq!(Some(12), bak_n_index(source_file, Path::new("/tmp/source.txt.bak.12")));
+        assert_eq!(None, bak_n_index(source_file, Path::new("/tmp/source.txt.bak")));
+        assert_eq!(None, bak_n_index(source_file, Path::new("/tmp/source.txt.bak.x")));
+        assert_eq!(None, bak_n_index(source_file, Path::new("/tmp/other.txt.bak.0")));
+    }
+}
@@ -0,0 +1,557 @@
+//! Journaled rotation of `.bak.N` files.
+//!
+//! The new backup is first written to a temporary file. A rotation is then planned as a list of
+//! renames within a single directory, ending with the rename of the new backup into place, which
+//! is written to a journal file and only then applied. Pruned backups are renamed aside rather
+//! than deleted, so that every step can be undone. Once every rename has been applied, the pruned
+//! backups and the journal are deleted, or the pruned backups are moved into the user's trash if
+//! the journal is marked `trash`. If any step fails, the renames are rolled back. If the
+//! process is interrupted, the journal is found by the next rotation: it is rolled forward if
+//! every rename was applied, and rolled back otherwise.
+//!
+//! If any of the backups have notes or keyed hashes, a copy of their manifest that follows the
+//! renames is written first, and renamed into place in the same way. The hash of an encrypted new
+//! backup is recorded in the copy, so that a backup is never in place without the record that it
+//! was encrypted.
+//!
+//! Backups that are moved into another directory are recorded by their full path. A move onto
+//! another file system is copied to a temporary file first, and the original is then pruned.
+//!
+//! ```text
+//! rename  foo.conf.bak.2            foo.conf.bak.2.pruned
+//! rename  foo.conf.bak.1            foo.conf.bak.2
+//! rename  foo.conf.bak              foo.conf.bak.1
+//! rename  foo.conf.bak9.notes       foo.conf.bak9.notes.pruned
+//! rename  foo.conf.bak9.notes.tmp   foo.conf.bak9.notes
+//! write   foo.conf.bak.0.tmp        foo.conf.bak.0
+//! ```
+
+use std::{fs, io::Write, path::{Path, PathBuf}};
+use crate::{crypt::HMAC, manifest::Manifest, notes::NOTES, os, Error, IoOp, PathExt, BAK9, E_FILENAME, E_STR, TMP};
+use super::Step;
+
+const JOURNAL: &str = "journal";
+const PRUNED: &str = "pruned";
+const RENAME: &str = "rename";
+const TRASH: &str = "trash";
+const WRITE: &str = "write";
+
+/// A planned rotation of the backups of a source file within a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    dir: PathBuf,
+    path: PathBuf,
+    source_filename: String,
+    /// Filenames to rename, in order, or the full paths of those in other directories
+    renames: Vec<(String, String)>,
+    /// Files to copy into other directories before renaming
+    copies: Vec<(String, String)>,
+    /// The filename of the new backup, if one is to be written
+    destination: Option<String>,
+    /// Whether pruned backups are moved into the user's trash
+    trash: bool,
+    /// The keyed hash of the new backup, if it is encrypted
+    hash: Option<String>
+}
+
+impl Journal {
+    pub fn new(source_file: &Path, dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            path: journal_path(source_file, dir),
+            source_filename: source_file.filename_string().expect(E_FILENAME),
+            renames: Vec::new(),
+            copies: Vec::new(),
+            destination: None,
+            trash: false,
+            hash: None
+        }
+    }
+
+    /// Moves pruned backups into the user's trash on commit, rather than deleting them.
+    pub fn with_trash(mut self, trash: bool) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    /// Records `hash` as the keyed hash of the new backup, which is encrypted. Otherwise, any hash
+    /// recorded under its name is forgotten.
+    pub fn with_hash(mut self, hash: Option<String>) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Plans to delete `filepath`. It is renamed aside until the rotation is committed.
+    pub fn prune(&mut self, filepath: &Path) {
+        let filename = filepath.filename_string().expect(E_FILENAME);
+        self.renames.push((filename.clone(), format!("{filename}.{PRUNED}")));
+    }
+
+    /// Plans to rename `from` to `to`.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        self.renames.push((from.filename_string().expect(E_FILENAME), to.filename_string().expect(E_FILENAME)));
+    }
+
+    /// Plans to move `from` within the directory to `to`, which may be in any other directory. If
+    /// it's on another file system, it's copied beside `to` before the journal is saved, and `from`
+    /// is pruned.
+    pub fn relocate(&mut self, from: &Path, to: &Path) {
+        let name = |path: &Path| match path.parent() == Some(self.dir.as_path()) {
+            true => path.filename_string().expect(E_FILENAME),
+            false => path.to_str().expect(E_STR).to_string()
+        };
+
+        let from_str = name(from);
+        let to_str = name(to);
+        let to_dir = to.parent().expect("Expected parent directory");
+
+        if os::is_same_file_system(&self.dir, to_dir) {
+            self.renames.push((from_str, to_str));
+        } else {
+            let tmp_str = format!("{to_str}.{TMP}");
+            self.copies.push((from_str.clone(), tmp_str.clone()));
+            self.renames.push((tmp_str, to_str));
+            self.renames.push((from_str.clone(), format!("{from_str}.{PRUNED}")));
+        }
+    }
+
+    /// Plans to write the new backup to `filepath`, once every rename has been applied.
+    pub fn write(&mut self, filepath: &Path) {
+        self.destination = Some(filepath.filename_string().expect(E_FILENAME));
+    }
+
+    /// The path that the new backup will be written to.
+    pub fn destination(&self) -> Option<PathBuf> {
+        self.destination.as_ref()
+            .map(|filename| self.dir.join(filename))
+    }
+
+    /// The steps that running the journal would perform, as seen from outside: pruned backups are
+    /// deleted and the new backup is copied from `source_file`.
+    pub fn plan(&self, source_file: &Path) -> Vec<Step> {
+        let manifest_filenames = self.manifest_filenames();
+        let mut steps: Vec<Step> = self.renames.iter()
+            .filter(|(from, _)| !manifest_filenames.iter().any(|filename| from.starts_with(filename)))
+            .map(|(from, to)| match to.strip_suffix(&format!(".{PRUNED}")) {
+                Some(_) => Step::Delete(self.dir.join(from)),
+                None => Step::Rename(self.dir.join(from), self.dir.join(to))
+            })
+            .collect();
+
+        if let Some(destination) = self.destination() {
+            steps.push(Step::Copy(source_file.to_path_buf(), destination));
+        }
+
+        steps
+    }
+
+    /// Every rename, ending with that of the new backup into place.
+    fn steps(&self) -> Vec<(String, String)> {
+        let mut steps = self.renames.clone();
+        if let Some(destination) = &self.destination {
+            steps.push((format!("{destination}.{TMP}"), destination.clone()));
+        }
+
+        steps
+    }
+
+    /// Writes the new backup to a temporary file with `write`, then journals and applies the
+    /// renames. Everything is rolled back if any step fails.
+    pub fn run(mut self, write: impl FnOnce(&Path) -> Result<(), Error>) -> Result<Option<PathBuf>, Error> {
+        let result = match self.destination() {
+            Some(destination) => write(&destination.append_extension(TMP)),
+            None => Ok(())
+        }
+        .and_then(|_| self.copy_files())
+        .and_then(|_| self.rotate_manifests())
+        .and_then(|_| self.save());
+
+        if let Err(e) = result {
+            self.remove_tmp_files();
+            return Err(e)
+        }
+
+        match self.apply() {
+            Ok(_) => {
+                self.commit()?;
+                Ok(self.destination())
+            },
+            Err(e) => {
+                self.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// The filenames of the manifests of the notes on the backups and of their keyed hashes, e.g.
+    /// `foo.conf.bak9.notes` and `foo.conf.bak9.hmac`.
+    fn manifest_filenames(&self) -> [String; 2] {
+        [NOTES, HMAC].map(|extension| self.path.with_extension(extension)
+            .filename_string().expect(E_FILENAME))
+    }
+
+    /// Writes a copy of each manifest whose entries follow the renames, and plans to rename it into
+    /// place after them. The manifest is renamed aside, like a pruned backup, so that it can be
+    /// restored.
+    fn rotate_manifests(&mut self) -> Result<(), Error> {
+        let [notes_filename, hashes_filename] = self.manifest_filenames();
+        let destination = self.destination();
+
+        for filename in [notes_filename, hashes_filename.clone()] {
+            let path = self.dir.join(&filename);
+            let is_hashes = filename == hashes_filename;
+            if !(path.exists() || is_hashes && self.hash.is_some()) {
+                continue
+            }
+
+            let mut manifest = Manifest::load(&path)?.rotate(&self.source_filename, &self.renames);
+            if let (true, Some(destination)) = (is_hashes, &destination) {
+                match &self.hash {
+                    Some(hash) => manifest.set(destination, hash),
+                    None => manifest.unset(destination)
+                }
+            }
+
+            if path.exists() {
+                self.renames.push((filename.clone(), format!("{filename}.{PRUNED}")));
+            }
+
+            if !manifest.is_empty() {
+                let tmp_filename = format!("{filename}.{TMP}");
+                manifest.save(&self.dir.join(&tmp_filename))?;
+                self.renames.push((tmp_filename, filename));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the files that are moved onto another file system to their temporary files.
+    fn copy_files(&self) -> Result<(), Error> {
+        for (from, to) in &self.copies {
+            let from = self.dir.join(from);
+            let to = self.dir.join(to);
+
+            match from.is_symlink() {
+                true => os::copy_link(&from, &to),
+                false => os::copy_file(&from, &to)
+            }
+            .map_err(|e| Error::copy(&from, &to, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the temporary files of the new backup, the copies and the rotated manifests, before
+    /// the journal was saved.
+    fn remove_tmp_files(&self) {
+        if let Some(destination) = self.destination() {
+            let _ = fs::remove_file(destination.append_extension(TMP));
+        }
+
+        for (_, to) in &self.copies {
+            let _ = fs::remove_file(self.dir.join(to));
+        }
+
+        for filename in self.manifest_filenames() {
+            let _ = fs::remove_file(self.dir.join(format!("{filename}.{TMP}")));
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        // pruned backups that are recovered go where they would have gone
+        let mut contents = match self.trash {
+            true => format!("{TRASH}\n"),
+            false => String::new()
+        };
+
+        for (from, to) in &self.renames {
+            contents.push_str(&format!("{RENAME}\t{from}\t{to}\n"));
+        }
+
+        if let Some(destination) = &self.destination {
+            contents.push_str(&format!("{WRITE}\t{destination}.{TMP}\t{destination}\n"));
+        }
+
+        fs::File::create(&self.path)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .map_err(|e| Error::io(IoOp::Write, &self.path, e))
+    }
+
+    fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::io(IoOp::Read, path, e))?;
+
+        let mut journal = Self {
+            dir: path.parent().expect("Expected parent directory").to_path_buf(),
+            path: path.to_path_buf(),
+            source_filename: String::new(),
+            renames: Vec::new(),
+            copies: Vec::new(),
+            destination: None,
+            trash: false,
+            hash: None
+        };
+
+        for line in contents.lines() {
+            match line.split('\t').collect::<Vec<&str>>().as_slice() {
+                [TRASH] => journal.trash = true,
+                [RENAME, from, to] => journal.renames.push((from.to_string(), to.to_string())),
+                [WRITE, _, destination] => journal.destination = Some(destination.to_string()),
+                _ => return Err(Error::Generic(format!("Invalid journal {}: {line}", path.to_str().expect(E_STR))))
+            }
+        }
+
+        Ok(journal)
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+        for (from, to) in self.steps() {
+            let to = self.dir.join(to);
+            fs::rename(self.dir.join(from), &to)
+                .map_err(|e| Error::io(IoOp::Rename, &to, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether every rename was applied. Renames are applied in order, and the source of the last
+    /// one can't be recreated by any other.
+    fn is_applied(&self) -> bool {
+        self.steps().last()
+            .is_none_or(|(from, _)| !super::exists(&self.dir.join(from)))
+    }
+
+    /// Deletes the pruned backups and then the journal.
+    fn commit(&self) -> Result<(), Error> {
+        let manifest_filenames = self.manifest_filenames();
+
+        for (from, to) in &self.renames {
+            let is_manifest = manifest_filenames.contains(from);
+            let to = self.dir.join(to);
+            if to.extension().is_some_and(|ext| ext == PRUNED) && super::exists(&to) {
+                if self.trash && !is_manifest {
+                    crate::trash::remove_file_as(&to, &self.dir.join(from))?;
+                } else {
+                    fs::remove_file(&to)
+                        .map_err(|e| Error::io(IoOp::Delete, &to, e))?;
+                }
+            }
+        }
+
+        self.remove()
+    }
+
+    /// Reverts every rename that was applied, newest first, then deletes the temporary files of
+    /// the new backup and the rotated manifests, and the journal.
+    fn rollback(&self) -> Result<(), Error> {
+        for (from, to) in self.renames.iter().rev() {
+            let from = self.dir.join(from);
+            let to = self.dir.join(to);
+
+            if super::exists(&to) && !super::exists(&from) {
+                fs::rename(&to, &from)
+                    .map_err(|e| Error::io(IoOp::Rename, &from, e))?;
+            }
+
+            if from.extension().is_some_and(|ext| ext == TMP) && super::exists(&from) {
+                fs::remove_file(&from)
+                    .map_err(|e| Error::io(IoOp::Delete, &from, e))?;
+            }
+        }
+
+        if let Some(destination) = self.destination() {
+            let tmp_path = destination.append_extension(TMP);
+            if super::exists(&tmp_path) {
+                fs::remove_file(&tmp_path)
+                    .map_err(|e| Error::io(IoOp::Delete, &tmp_path, e))?;
+            }
+        }
+
+        self.remove()
+    }
+
+    fn remove(&self) -> Result<(), Error> {
+        fs::remove_file(&self.path)
+            .map_err(|e| Error::io(IoOp::Delete, &self.path, e))
+    }
+}
+
+/// The journal file of the backups of `source_file` within `dir`, e.g. `foo.conf.bak9.journal`.
+fn journal_path(source_file: &Path, dir: &Path) -> PathBuf {
+    dir.join(source_file.filename_str().expect(E_FILENAME))
+        .append_extension(BAK9)
+        .append_extension(JOURNAL)
+}
+
+/// Completes or rolls back a rotation that was interrupted. Returns whether there was one.
+pub fn recover(source_file: &Path, dir: &Path) -> Result<bool, Error> {
+    let path = journal_path(source_file, dir);
+    if !path.is_file() {
+        return Ok(false)
+    }
+
+    let journal = Journal::load(&path)?;
+
+    if journal.is_applied() {
+        journal.commit()?;
+    } else {
+        journal.rollback()?;
+    }
+
+    // ensure that the recovered renames survive another crash
+    let _ = os::sync_file(dir);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let dir = crate::test_dir("test_journal_recover");
+
+        let source = dir.join("source.txt");
+        fs::write(dir.join("source.txt.bak.0"), "0").unwrap();
+        fs::write(dir.join("source.txt.bak.1"), "1").unwrap();
+
+        let mut journal = Journal::new(&source, &dir);
+        journal.prune(&dir.join("source.txt.bak.1"));
+        journal.rename(&dir.join("source.txt.bak.0"), &dir.join("source.txt.bak.1"));
+        journal.write(&dir.join("source.txt.bak.0"));
+
+        // interrupted after the renames, but before the new backup was renamed into place
+        fs::write(dir.join("source.txt.bak.0.tmp"), "new").unwrap();
+        journal.save().unwrap();
+        for (from, to) in &journal.renames {
+            fs::rename(dir.join(from), dir.join(to)).unwrap();
+        }
+        assert!(!dir.join("source.txt.bak.0").exists());
+
+        assert!(recover(&source, &dir).unwrap());
+        assert_eq!("0", fs::read_to_string(dir.join("source.txt.bak.0")).unwrap());
+        assert_eq!("1", fs::read_to_string(dir.join("source.txt.bak.1")).unwrap());
+        assert!(!dir.join("source.txt.bak.0.tmp").exists());
+        assert!(!journal_path(&source, &dir).exists());
+        assert!(!recover(&source, &dir).unwrap());
+
+        // interrupted after the new backup was renamed into place, but before the commit
+        fs::write(dir.join("source.txt.bak.0.tmp"), "new").unwrap();
+        journal.save().unwrap();
+        journal.apply().unwrap();
+
+        assert!(recover(&source, &dir).unwrap());
+        assert_eq!("new", fs::read_to_string(dir.join("source.txt.bak.0")).unwrap());
+        assert_eq!("0", fs::read_to_string(dir.join("source.txt.bak.1")).unwrap());
+        assert!(!dir.join("source.txt.bak.1.pruned").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_trash() {
+        let dir = crate::test_dir("test_journal_save_trash");
+        let source = dir.join("source.txt");
+
+        // a recovered rotation trashes its pruned backups, like the interrupted one would have
+        for trash in [false, true] {
+            let mut journal = Journal::new(&source, &dir).with_trash(trash);
+            journal.prune(&dir.join("source.txt.bak.1"));
+            journal.save().unwrap();
+
+            let loaded = Journal::load(&journal.path).unwrap();
+            assert_eq!(trash, loaded.trash);
+            assert_eq!(journal.renames, loaded.renames);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relocate() {
+        let dir = crate::test_dir("test_journal_relocate");
+        let new_dir = dir.join("new");
+        fs::create_dir(&new_dir).unwrap();
+        fs::write(dir.join("source.txt.bak"), "0").unwrap();
+        fs::write(dir.join("source.txt.bak.1"), "1").unwrap();
+
+        let new_source = new_dir.join("moved.txt");
+        let mut journal = Journal::new(&new_source, &new_dir);
+        journal.relocate(&dir.join("source.txt.bak"), &new_dir.join("moved.txt.bak"));
+        assert_eq!(dir.join("source.txt.bak").to_str().unwrap(), journal.renames[0].0);
+        assert_eq!("moved.txt.bak", journal.renames[0].1);
+
+        // as if onto another file system
+        journal.copies.push(("../source.txt.bak.1".to_string(), "moved.txt.bak.1.tmp".to_string()));
+        journal.renames.push(("moved.txt.bak.1.tmp".to_string(), "moved.txt.bak.1".to_string()));
+        journal.renames.push(("../source.txt.bak.1".to_string(), "../source.txt.bak.1.pruned".to_string()));
+
+        // interrupted after the first move
+        journal.copy_files().unwrap();
+        journal.save().unwrap();
+        fs::rename(dir.join("source.txt.bak"), new_dir.join("moved.txt.bak")).unwrap();
+
+        assert!(recover(&new_source, &new_dir).unwrap());
+        assert_eq!("0", fs::read_to_string(dir.join("source.txt.bak")).unwrap());
+        assert_eq!("1", fs::read_to_string(dir.join("source.txt.bak.1")).unwrap());
+        assert!(!new_dir.join("moved.txt.bak").exists());
+        assert!(!new_dir.join("moved.txt.bak.1.tmp").exists());
+
+        journal.run(|_| Ok(())).unwrap();
+        assert_eq!("0", fs::read_to_string(new_dir.join("moved.txt.bak")).unwrap());
+        assert_eq!("1", fs::read_to_string(new_dir.join("moved.txt.bak.1")).unwrap());
+        assert!(!dir.join("source.txt.bak.1").exists());
+        assert!(!dir.join("source.txt.bak.1.pruned").exists());
+        assert!(!journal_path(&new_source, &new_dir).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = crate::test_dir("test_journal_rollback");
+
+        let source = dir.join("source.txt");
+        fs::write(dir.join("source.txt.bak"), "0").unwrap();
+
+        let mut journal = Journal::new(&source, &dir);
+        journal.rename(&dir.join("source.txt.bak"), &dir.join("source.txt.bak.1"));
+        journal.write(&dir.join("source.txt.bak.0"));
+
+        let notes_path = dir.join("source.txt.bak9.notes");
+        let notes = "bak = \"first\"\n";
+        fs::write(&notes_path, notes).unwrap();
+
+        let result = journal.clone().run(|_| Err(Error::Generic("disk full".to_string())));
+        assert!(result.is_err());
+        assert_eq!("0", fs::read_to_string(dir.join("source.txt.bak")).unwrap());
+        assert!(!journal_path(&source, &dir).exists());
+        assert!(!dir.join("source.txt.bak9.notes.tmp").exists());
+
+        // the new backup can't be renamed into place, after the older backups were shifted
+        fs::create_dir(dir.join("source.txt.bak.0")).unwrap();
+        let result = journal.run(|tmp_path| fs::write(tmp_path, "new").map_err(|e| Error::io(IoOp::Write, tmp_path, e)));
+        assert!(result.is_err());
+        assert_eq!("0", fs::read_to_string(dir.join("source.txt.bak")).unwrap());
+        assert!(!dir.join("source.txt.bak.1").exists());
+        assert!(!dir.join("source.txt.bak.0.tmp").exists());
+        assert!(!journal_path(&source, &dir).exists());
+        assert_eq!(notes, fs::read_to_string(&notes_path).unwrap());
+        assert!(!dir.join("source.txt.bak9.notes.tmp").exists());
+
+        // the notes follow the renames once they succeed
+        fs::remove_dir(dir.join("source.txt.bak.0")).unwrap();
+        let mut journal = Journal::new(&source, &dir);
+        journal.rename(&dir.join("source.txt.bak"), &dir.join("source.txt.bak.1"));
+        journal.write(&dir.join("source.txt.bak.0"));
+        journal.run(|tmp_path| fs::write(tmp_path, "new").map_err(|e| Error::io(IoOp::Write, tmp_path, e))).unwrap();
+        assert_eq!("\"bak.1\" = \"first\"\n", fs::read_to_string(&notes_path).unwrap());
+        assert!(!dir.join("source.txt.bak9.notes.pruned").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+//! Keeps every backup of a file as a member of a single `.bak9.tar` archive.
+
+use std::{fs, io::{self, Read, Write}, ops::Range, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use crate::{crypt::{self, Encryption}, manifest::Manifest, os, progress::{Progress, ProgressReader}, Error, IoOp, PathExt, BAK9, E_FILENAME, TMP};
+use super::{BackupStore, Step};
+
+const TAR: &str = "tar";
+const TAR_GZ: &str = "tar.gz";
+
+/// The archive format used by a [TarStore].
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Archive {
+    /// An uncompressed `.bak9.tar` archive
+    Tar,
+    /// A gzip compressed `.bak9.tar.gz` archive
+    #[value(name = "tar.gz")]
+    TarGz
+}
+
+impl Archive {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Tar => TAR,
+            Self::TarGz => TAR_GZ
+        }
+    }
+}
+
+/// A single version of the source file held within the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub mtime: u64,
+    pub size: u64,
+    /// The permission bits of the source file when it was backed up
+    pub mode: u32
+}
+
+/// Stores backups as members of a single archive, e.g. `foo.conf.bak9.tar`, in a directory.
+/// Members are appended in order, each named after the source file and the time it was taken.
+pub struct TarStore {
+    source_file: PathBuf,
+    dir: PathBuf,
+    archive: Archive,
+    encryption: Option<Encryption>
+}
+
+impl TarStore {
+    pub fn new(source_file: &Path, dir: &Path, archive: Archive) -> Self {
+        Self { source_file: source_file.to_path_buf(), dir: dir.to_path_buf(), archive, encryption: None }
+    }
+
+    /// Encrypts each new member.
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// The path of the archive file.
+    pub fn archive_path(&self) -> PathBuf {
+        self.dir.join(self.source_file.filename_str().expect(E_FILENAME))
+            .append_extension(BAK9)
+            .append_extension(self.archive.extension())
+    }
+
+    /// Lists all members of the archive, oldest first.
+    pub fn members(&self) -> Result<Vec<Member>, Error> {
+        let archive_path = self.archive_path();
+        if !archive_path.exists() {
+            return Ok(Vec::new())
+        }
+
+        let mut members = Vec::new();
+        let mut tar = self.open_archive()?;
+        let entries = tar.entries()
+            .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+            let header = entry.header();
+            members.push(Member {
+                name: String::from_utf8_lossy(&entry.path_bytes()).to_string(),
+                mtime: header.mtime().unwrap_or(0),
+                size: header.size().unwrap_or(0),
+                mode: header.mode().unwrap_or(0o600)
+            });
+        }
+
+        Ok(members)
+    }
+
+    fn open_archive(&self) -> Result<tar::Archive<Box<dyn Read>>, Error> {
+        let archive_path = self.archive_path();
+        let file = fs::File::open(&archive_path)
+            .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+
+        let reader: Box<dyn Read> = match self.archive {
+            Archive::Tar => Box::new(io::BufReader::new(file)),
+            Archive::TarGz => Box::new(GzDecoder::new(io::BufReader::new(file)))
+        };
+
+        Ok(tar::Archive::new(reader))
+    }
+
+    /// Streams the contents of the member at archive `position`, oldest first, to `read`.
+    fn read_member<T>(&self, position: usize, read: impl FnOnce(&mut dyn Read) -> Result<T, Error>) -> Result<T, Error> {
+        let archive_path = self.archive_path();
+        let mut tar = self.open_archive()?;
+        let mut entry = tar.entries()
+            .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?
+            .nth(position)
+            .ok_or_else(|| Error::Generic(format!("Missing archive member: {position}")))?
+            .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+
+        read(&mut entry)
+    }
+
+    /// The archive position of the member at `index`, where `0` is the most recent.
+    fn position(&self, members: &[Member], index: u8) -> Result<usize, Error> {
+        members.len().checked_sub(index as usize + 1)
+            .ok_or_else(|| Error::index(&self.source_file, index))
+    }
+
+    /// Rewrites the archive, keeping only the members at the `keep` positions (oldest first) and
+    /// then appending the source file as a new member if `append` is set. The archive is written to
+    /// a temporary file first and then renamed into place, so that it is left as it was if any
+    /// step fails. The archive is only accessible by the user, since its members may have had
+    /// differing permissions.
+    fn rewrite(&self, keep: Range<usize>, append: Option<&str>) -> Result<(), Error> {
+        let archive_path = self.archive_path();
+        let tmp_path = archive_path.clone().append_extension(TMP);
+        let total = self.members()?.len();
+
+        let result = self.write_archive(keep.clone(), append, &tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, &archive_path)
+                .map_err(|e| Error::io(IoOp::Rename, &archive_path, e)));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            let _ = fs::remove_file(tmp_path.append_extension(TMP));
+            return result
+        }
+
+        // the name of a pruned member may be reused, so its note and hash are forgotten with it
+        if keep.len() < total {
+            let members = self.list()?;
+            for path in [crate::notes::manifest_path(self), crypt::hash_path(&self.source_file, &archive_path)] {
+                if path.exists() {
+                    let mut manifest = Manifest::load(&path)?;
+                    manifest.retain(&members);
+                    manifest.save(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the rewritten archive to `tmp_path`. The encrypted new member is staged beside it,
+    /// in `tmp_path.tmp`.
+    fn write_archive(&self, keep: Range<usize>, append: Option<&str>, tmp_path: &Path) -> Result<(), Error> {
+        let archive_path = self.archive_path();
+        let total = self.members()?.len();
+
+        let file = os::create_private_file(tmp_path)
+            .map_err(|e| Error::io(IoOp::Create, tmp_path, e))?;
+        let writer: Box<dyn Write> = match self.archive {
+            Archive::Tar => Box::new(io::BufWriter::new(file)),
+            Archive::TarGz => Box::new(GzEncoder::new(io::BufWriter::new(file), flate2::Compression::default()))
+        };
+
+        let mut builder = tar::Builder::new(writer);
+
+        if total > 0 {
+            let mut tar = self.open_archive()?;
+            let entries = tar.entries()
+                .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+
+            for entry in entries.skip(keep.start).take(keep.len()) {
+                let mut entry = entry.map_err(|e| Error::io(IoOp::Read, &archive_path, e))?;
+                let mut header = entry.header().clone();
+                let member_path = entry.path()
+                    .map_err(|e| Error::io(IoOp::Read, &archive_path, e))?
+                    .to_path_buf();
+                builder.append_data(&mut header, member_path, &mut entry)
+                    .map_err(|e| Error::io(IoOp::Write, tmp_path, e))?;
+            }
+        }
+
+        if let Some(member_name) = append {
+            let metadata = fs::metadata(&self.source_file)
+                .map_err(|e| Error::io(IoOp::Read, &self.source_file, e))?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            header.set_mtime(now());
+
+            // the encrypted member is staged in a file of its own, since its size must be known first
+            let encrypted_path = tmp_path.append_extension(TMP);
+            let member_path = match &self.encryption {
+                Some(encryption) => {
+                    encryption.encrypt_file(&self.source_file, &encrypted_path)?;
+                    let encrypted_size = fs::metadata(&encrypted_path)
+                        .map_err(|e| Error::io(IoOp::Read, &encrypted_path, e))?
+                        .len();
+                    header.set_size(encrypted_size);
+                    &encrypted_path
+                },
+                None => &self.source_file
+            };
+
+            let source = fs::File::open(member_path)
+                .map_err(|e| Error::io(IoOp::Read, member_path, e))?;
+            let progress = Progress::new(self.source_file.filename_str().expect(E_FILENAME), header.size().unwrap_or(0));
+            let result = builder.append_data(&mut header, member_name, ProgressReader::new(source, progress))
+                .map_err(|e| Error::copy(&self.source_file, &archive_path, e));
+
+            if member_path == &encrypted_path {
+                let _ = fs::remove_file(&encrypted_path);
+            }
+
+            result?;
+        }
+
+        builder.into_inner()
+            .and_then(|mut writer| writer.flush())
+            .and_then(|_| os::sync_file(tmp_path))
+            .map_err(|e| Error::io(IoOp::Write, tmp_path, e))
+    }
+
+    /// The positions of the members to keep to make room for a new one, keeping at most `max`, and
+    /// the path of the new member. Returns `None` if the source file is unchanged since the newest.
+    fn plan_rotation(&self, max: u8) -> Result<Option<(Range<usize>, PathBuf)>, Error> {
+        super::remove_stale_tmp_files(&self.source_file, &self.dir)?;
+        let members = self.members()?;
+
+        if self.is_unchanged(&members)? {
+            return Ok(None)
+        }
+
+        let prune_amount = (members.len() + 1).saturating_sub(max as usize);
+        Ok(Some((prune_amount.min(members.len())..members.len(), self.archive_path().join(self.member_name(now(), &members)))))
+    }
+
+    /// Whether the source file is identical to the newest of `members`.
+    fn is_unchanged(&self, members: &[Member]) -> Result<bool, Error> {
+        if members.is_empty() {
+            return Ok(false)
+        }
+
+        let source = fs::File::open(&self.source_file)
+            .map_err(|e| Error::io(IoOp::Read, &self.source_file, e))?;
+
+        // an encrypted member can't be compared, so its hash is instead
+        let archive_path = self.archive_path();
+        let member_filepath = archive_path.join(&members[members.len() - 1].name);
+        if crypt::is_encrypted(&self.source_file, &member_filepath)? {
+            return crypt::matches_hash(&self.source_file, &member_filepath)
+        }
+
+        self.read_member(members.len() - 1, |member| {
+            crate::diff_readers(io::BufReader::new(member), io::BufReader::new(source))
+                .map(|is_changed| !is_changed)
+                .map_err(|e| Error::io(IoOp::Read, &archive_path, e))
+        })
+    }
+
+    /// Writes the contents of the member at `index` to `file` at `path`, with the time that it was
+    /// backed up.
+    fn write_member(&self, members: &[Member], index: u8, mut file: fs::File, path: &Path) -> Result<(), Error> {
+        let position = self.position(members, index)?;
+        self.read_member(position, |member| io::copy(member, &mut file)
+            .map_err(|e| Error::io(IoOp::Write, path, e)))?;
+
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(members[position].mtime))
+            .map_err(|e| Error::io(IoOp::Write, path, e))
+    }
+
+    /// Names a new member after the source file and `mtime`, made unique amongst `members`.
+    fn member_name(&self, mtime: u64, members: &[Member]) -> String {
+        let filename = self.source_file.filename_str().expect(E_FILENAME);
+        let mut name = format!("{filename}.{mtime}");
+        let mut n = 0;
+
+        while members.iter().any(|member| member.name == name) {
+            n += 1;
+            name = format!("{filename}.{mtime}-{n}");
+        }
+
+        name
+    }
+}
+
+impl BackupStore for TarStore {
+    fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    fn location(&self) -> PathBuf {
+        self.archive_path()
+    }
+
+    /// Members are listed as paths within the archive, e.g. `foo.conf.bak9.tar/foo.conf.1700000000`.
+    fn list(&self) -> Result<Vec<PathBuf>, Error> {
+        let archive_path = self.archive_path();
+
+        Ok(self.members()?
+            .into_iter()
+            .rev()
+            .map(|member| archive_path.join(member.name))
+            .collect())
+    }
+
+    /// Extracts the member into a private temporary directory, which is deleted when it is
+    /// dropped, with the permissions that the source file had when it was backed up and the time
+    /// that it was backed up.
+    fn get(&self, index: u8) -> Result<super::BackupFile, Error> {
+        let members = self.members()?;
+        let member = &members[self.position(&members, index)?];
+
+        let (extracted, file) = os::PrivateTempFile::create(&member.name)
+            .map_err(|e| Error::io(IoOp::Create, &self.archive_path(), e))?;
+        self.write_member(&members, index, file, extracted.path())?;
+        set_mode(extracted.path(), member.mode)
+            .map_err(|e| Error::io(IoOp::Write, extracted.path(), e))?;
+
+        Ok(super::BackupFile::extracted(extracted))
+    }
+
+    fn read(&self, index: u8, read: &mut dyn FnMut(&mut dyn Read) -> Result<(), Error>) -> Result<(), Error> {
+        let members = self.members()?;
+        self.read_member(self.position(&members, index)?, |member| read(member))
+    }
+
+    /// Streams the member into a temporary file beside `dest`, which is then renamed into place,
+    /// with the permissions that the source file had when it was backed up.
+    fn restore(&self, index: u8, dest: &Path) -> Result<(), Error> {
+        let members = self.members()?;
+        let member = &members[self.position(&members, index)?];
+        let (tmp_path, file) = os::create_tmp_beside(dest, os::create_new_private_file)
+            .map_err(|e| Error::io(IoOp::Create, dest, e))?;
+
+        let result = self.write_member(&members, index, file, &tmp_path)
+            .and_then(|_| set_mode(&tmp_path, member.mode)
+                .and_then(|_| os::sync_file(&tmp_path))
+                .and_then(|_| fs::rename(&tmp_path, dest))
+                .map_err(|e| Error::io(IoOp::Write, dest, e)));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    /// The times that the members were appended, without extracting them.
+    fn timestamps(&self) -> Result<Vec<Option<SystemTime>>, Error> {
+        Ok(self.members()?
+            .into_iter()
+            .rev()
+            .map(|member| Some(UNIX_EPOCH + Duration::from_secs(member.mtime)))
+            .collect())
+    }
+
+    /// The sizes of the members, without extracting them.
+    fn sizes(&self) -> Result<Vec<u64>, Error> {
+        Ok(self.members()?
+            .into_iter()
+            .rev()
+            .map(|member| member.size)
+            .collect())
+    }
+
+    fn rotate(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        let (keep, member_filepath) = match self.plan_rotation(max)? {
+            Some(rotation) => rotation,
+            None => return Ok(None)
+        };
+
+        if keep.start > 0 {
+            self.rewrite(keep, None)?;
+        }
+
+        Ok(Some(member_filepath))
+    }
+
+    /// Prunes the oldest members and appends the new one in a single rewrite, so that a failure
+    /// to append leaves every member in place.
+    fn put(&self, max: u8) -> Result<Option<PathBuf>, Error> {
+        let (keep, member_filepath) = match self.plan_rotation(max)? {
+            Some(rotation) => rotation,
+            None => return Ok(None)
+        };
+
+        // the hash is recorded first, so that an encrypted member is never without it
+        let is_encrypted = self.encryption.is_some();
+        if is_encrypted {
+            crypt::update_hash(&self.source_file, &member_filepath, true)?;
+        }
+
+        if let Err(e) = self.rewrite(keep, Some(member_filepath.filename_str().expect(E_FILENAME))) {
+            if is_encrypted {
+                let _ = crypt::update_hash(&self.source_file, &member_filepath, false);
+            }
+
+            return Err(e)
+        }
+
+        Ok(Some(member_filepath))
+    }
+
+    fn plan_put(&self, max: u8) -> Result<Vec<Step>, Error> {
+        let members = self.members()?;
+
+        if self.is_unchanged(&members)? {
+            return Ok(Vec::new())
+        }
+
+        let archive_path = self.archive_path();
+        let prune_amount = (members.len() + 1).saturating_sub(max as usize);
+        let mut steps: Vec<Step> = members.iter()
+            .take(prune_amount)
+            .map(|member| Step::Delete(archive_path.join(&member.name)))
+            .collect();
+
+        steps.push(Step::Copy(self.source_file.clone(), archive_path.join(self.member_name(now(), &members))));
+        Ok(steps)
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>, Error> {
+        let archive_path = self.archive_path();
+        Ok(if archive_path.exists() { vec![archive_path] } else { Vec::new() })
+    }
+
+    fn discard(&self) -> Result<(), Error> {
+        match self.members()?.len() {
+            0 => Ok(()),
+            1 => self.delete(),
+            total => self.rewrite(0..total - 1, None)
+        }
+    }
+
+    fn trash(&self) -> Result<(), Error> {
+        let archive_path = self.archive_path();
+        if archive_path.exists() {
+            crate::trash::remove_file(&archive_path)?;
+        }
+
+        crypt::remove_hash(&self.source_file, &archive_path)
+    }
+
+    fn delete(&self) -> Result<(), Error> {
+        let archive_path = self.archive_path();
+        if archive_path.exists() {
+            fs::remove_file(&archive_path)
+                .map_err(|e| Error::io(IoOp::Delete, &archive_path, e))?;
+        }
+
+        crypt::remove_hash(&self.source_file, &archive_path)
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,188 @@
+//! Moves deleted backups into the user's trash, following the freedesktop.org Trash
+//! specification, so that they can be recovered with standard desktop tools.
+//!
+//! A trashed file is moved into `$XDG_DATA_HOME/Trash/files`, along with a `.trashinfo` file in
+//! `$XDG_DATA_HOME/Trash/info` that records its original path and deletion date. A file on another
+//! mount is moved into `$topdir/.Trash-$uid` at the top of that mount instead, where it's recorded
+//! relative to `$topdir`, unless that can't be created.
+//!
+//! Only Linux has such a trash. Elsewhere, trashing fails rather than deleting permanently.
+
+use std::{fs, io, path::{Path, PathBuf}};
+use crate::{os, Error, IoOp, PathExt, E_FILENAME, E_STR};
+
+const TRASH: &str = "Trash";
+const FILES: &str = "files";
+const INFO: &str = "info";
+const TRASHINFO: &str = "trashinfo";
+
+/// Whether this system has a trash to move files into.
+pub fn is_available() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// The user's home trash directory, `$XDG_DATA_HOME/Trash`.
+pub fn user_trash_dir() -> Result<PathBuf, Error> {
+    os::user_app_data_dir(true, TRASH.into())
+        .map_err(|e| Error::Generic(e.to_string()))
+}
+
+/// Moves `path` into the user's trash. Fails on systems without a freedesktop.org trash.
+pub fn remove_file(path: &Path) -> Result<(), Error> {
+    remove_file_as(path, path)
+}
+
+/// Moves `path` into the user's trash, recorded as having been deleted from `original_path`.
+pub fn remove_file_as(path: &Path, original_path: &Path) -> Result<(), Error> {
+    if !is_available() {
+        return Err(Error::Generic(format!("No trash is available on this system to move {} into",
+            path.to_str().expect(E_STR))))
+    }
+
+    let home_trash_dir = user_trash_dir()?;
+    match topdir_trash_dir(path, &home_trash_dir) {
+        Some((topdir, trash_dir)) => put(&trash_dir, Some(&topdir), path, original_path),
+        None => put(&home_trash_dir, None, path, original_path)
+    }.map(|_| ())
+}
+
+/// The trash directory at the top of the mount that `path` is on, along with that top directory,
+/// if it's not the mount of `home_trash_dir` and the trash directory can be created.
+#[cfg(unix)]
+fn topdir_trash_dir(path: &Path, home_trash_dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = std::path::absolute(path).ok()?;
+    let dev = fs::symlink_metadata(&path).ok()?.dev();
+    if dev == fs::metadata(home_trash_dir).ok()?.dev() {
+        return None
+    }
+
+    let topdir = path.ancestors()
+        .skip(1)
+        .take_while(|dir| fs::metadata(dir).is_ok_and(|metadata| metadata.dev() == dev))
+        .last()?
+        .to_path_buf();
+
+    // SAFETY: getuid never fails
+    let trash_dir = topdir.join(format!(".{TRASH}-{}", unsafe { libc::getuid() }));
+    os::create_private_dir_all(&trash_dir).ok()?;
+    Some((topdir, trash_dir))
+}
+
+#[cfg(not(unix))]
+fn topdir_trash_dir(_path: &Path, _home_trash_dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    None
+}
+
+/// Moves `path` into `trash_dir`, returning its path within the trash. It is named and recorded
+/// after `original_path`, relative to `topdir` if the trash is at the top of a mount.
+pub fn put(trash_dir: &Path, topdir: Option<&Path>, path: &Path, original_path: &Path) -> Result<PathBuf, Error> {
+    let files_dir = trash_dir.join(FILES);
+    let info_dir = trash_dir.join(INFO);
+
+    for dir in [&files_dir, &info_dir] {
+        os::create_private_dir_all(dir)
+            .map_err(|e| Error::io(IoOp::Create, dir, e))?;
+    }
+
+    let original_path = std::path::absolute(original_path)
+        .map_err(|e| Error::io(IoOp::Read, original_path, e))?;
+    let recorded_path = topdir
+        .and_then(|topdir| original_path.strip_prefix(topdir).ok())
+        .unwrap_or(&original_path);
+    let trashinfo = format!("[Trash Info]\nPath={}\nDeletionDate={}\n",
+        encode_path(recorded_path),
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"));
+
+    // the info file is created exclusively first, to claim a name that's unique within the trash
+    let filename = original_path.filename_str().expect(E_FILENAME);
+    let mut n = 1;
+    let (trashed_path, info_path) = loop {
+        let name = if n == 1 { filename.to_string() } else { format!("{filename}.{n}") };
+        let info_path = info_dir.join(&name).append_extension(TRASHINFO);
+
+        match fs::OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(_) if !files_dir.join(&name).exists() => break (files_dir.join(&name), info_path),
+            Ok(_) => {
+                let _ = fs::remove_file(&info_path);
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+            Err(e) => return Err(Error::io(IoOp::Create, &info_path, e))
+        }
+
+        n += 1;
+    };
+
+    let result = fs::write(&info_path, trashinfo)
+        .map_err(|e| Error::io(IoOp::Write, &info_path, e))
+        .and_then(|_| move_file(path, &trashed_path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&info_path);
+    }
+
+    result.map(|_| trashed_path)
+}
+
+/// Renames `from` to `to`, or copies and deletes it if they are on different filesystems.
+fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(())
+    }
+
+    os::copy_file(from, to)
+        .map_err(|e| Error::copy(from, to, e))?;
+    fs::remove_file(from)
+        .map_err(|e| Error::io(IoOp::Delete, from, e))
+}
+
+/// Percent-encodes a path for the `Path` key of a `.trashinfo` file.
+fn encode_path(path: &Path) -> String {
+    path.to_str().expect(E_STR)
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!("/home/dev/a%20b.txt.bak.0", encode_path(Path::new("/home/dev/a b.txt.bak.0")));
+    }
+
+    #[test]
+    fn test_put() {
+        let dir = crate::test_dir("test_trash_put");
+        let trash_dir = dir.join(TRASH);
+
+        let file = dir.join("source.txt.bak");
+        fs::write(&file, "1").unwrap();
+        assert_eq!(trash_dir.join(FILES).join("source.txt.bak"), put(&trash_dir, None, &file, &file).unwrap());
+        assert!(!file.exists());
+
+        let pruned_file = dir.join("source.txt.bak.pruned");
+        fs::write(&pruned_file, "2").unwrap();
+        assert_eq!(trash_dir.join(FILES).join("source.txt.bak.2"), put(&trash_dir, None, &pruned_file, &file).unwrap());
+
+        let trashinfo = fs::read_to_string(trash_dir.join(INFO).join("source.txt.bak.2.trashinfo")).unwrap();
+        assert!(trashinfo.starts_with("[Trash Info]\n"));
+        assert!(trashinfo.contains(&format!("Path={}\n", encode_path(&file))));
+        assert_eq!("2", fs::read_to_string(trash_dir.join(FILES).join("source.txt.bak.2")).unwrap());
+
+        // a trash at the top of a mount records paths relative to it
+        let file = dir.join("other.txt.bak");
+        fs::write(&file, "3").unwrap();
+        put(&trash_dir, Some(&dir), &file, &file).unwrap();
+        let trashinfo = fs::read_to_string(trash_dir.join(INFO).join("other.txt.bak.trashinfo")).unwrap();
+        assert!(trashinfo.contains("Path=other.txt.bak\n"), "{trashinfo}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
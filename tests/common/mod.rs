@@ -1,7 +1,6 @@
 #![allow(dead_code)]
 
 use std::path::PathBuf;
-use file_diff;
 
 pub use function_name::named;
 
@@ -122,7 +122,7 @@ pub mod tests {
     }
 
     fn wipe_source(source_filepath: &Path) {
-        cmd(true, &["-qf", source_filepath.to_str().unwrap(), "rm"]);
+        cmd(true, &["-qf", "--no-trash", source_filepath.to_str().unwrap(), "rm"]);
     }
 
     #[named]
@@ -163,7 +163,7 @@ pub mod tests {
 
         //STEP: Wipe them all. Run a list again.
         //RESULT: List should be empty
-        let (stdout, stderr) = cmd(true, &["-qf", source_filepath.to_str().unwrap(), "rm"]);
+        let (stdout, stderr) = cmd(true, &["-qf", "--no-trash", source_filepath.to_str().unwrap(), "rm"]);
         assert!(stdout.is_empty(), "stdout: {}", stdout);
         assert!(stderr.is_empty(), "stderr: {}", stderr);
         let (stdout, stderr) = cmd(true, &[source_filepath.to_str().unwrap(), "ls"]);
@@ -196,11 +196,810 @@ pub mod tests {
         assert!(stderr.is_empty(), "stderr: {}", stderr);
         let last_line = *stdout.trim().lines().collect::<Vec<&str>>().last().unwrap();
         assert!(
-            last_line == &format!("\u{1b}[32m+\u{1b}[m\u{1b}[32m{}\u{1b}[m", TESTING_CONTENT) // git diff
-            || last_line == &format!("\u{1b}[32m+ {}\u{1b}[0m", TESTING_CONTENT) // gnu diff
-            || last_line == &format!("{} =>", TESTING_CONTENT), // windows diff
+            last_line == format!("\u{1b}[32m+\u{1b}[m\u{1b}[32m{}\u{1b}[m", TESTING_CONTENT) // git diff
+            || last_line == format!("\u{1b}[32m+ {}\u{1b}[0m", TESTING_CONTENT) // gnu diff
+            || last_line == format!("{} =>", TESTING_CONTENT), // windows diff
         );
 
         close_tmpdir(function_name!());
     }
+
+    /// Runs the command with its own XDG data directory, isolating the app data mirror
+    #[cfg(target_os = "linux")]
+    fn cmd_xdg<S: AsRef<std::ffi::OsStr>>(success: bool, xdg_data_home: &Path, args: &[S]) -> (String, String) {
+        let output = process::Command::new(BIN_EXE)
+            .env("XDG_DATA_HOME", xdg_data_home)
+            .args(args)
+            .output()
+            .unwrap();
+
+        assert_eq!(success, output.status.success());
+        (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_gc() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let kept_filepath = tmpfile_append(TESTING_CONTENT, "kept.txt", function_name!());
+
+        //PREP: Backup both files to the app data dir, then delete one of them
+        cmd_xdg(true, &xdg_data_home, &[source_filepath.to_str().unwrap(), "-"]);
+        cmd_xdg(true, &xdg_data_home, &[kept_filepath.to_str().unwrap(), "-"]);
+        std::fs::remove_file(&source_filepath).unwrap();
+
+        //STEP: Collect garbage
+        //RESULT: Only the orphaned backup should be deleted
+        let (stdout, stderr) = cmd_xdg(true, &xdg_data_home, &["-f", "gc"]);
+        assert!(stderr.is_empty(), "stderr: {}", stderr);
+        assert!(stdout.contains(SOURCE_TXT), "stdout: {}", stdout);
+        assert!(!stdout.contains("kept.txt"), "stdout: {}", stdout);
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["ls", "--all"]);
+        assert!(!stdout.contains(SOURCE_TXT), "stdout: {}", stdout);
+        assert!(stdout.contains("kept.txt"), "stdout: {}", stdout);
+
+        //STEP: Delete the other file and collect garbage again
+        //RESULT: The mirror tree should be emptied
+        std::fs::remove_file(&kept_filepath).unwrap();
+        cmd_xdg(true, &xdg_data_home, &["-qf", "gc"]);
+        let mirror_dir = xdg_data_home.join("bak9").join(tmpdir.strip_prefix("/").unwrap());
+        assert!(!mirror_dir.exists());
+        assert!(xdg_data_home.join("bak9").is_dir());
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_mv() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let moved_filepath = tmpdir.join("moved.txt");
+
+        //PREP: Backup source.txt beside itself with a note and to the app data dir, then move it
+        cmd_xdg(true, &xdg_data_home, &["-m", "first", source_filepath.to_str().unwrap()]);
+        cmd_xdg(true, &xdg_data_home, &[source_filepath.to_str().unwrap(), "-"]);
+        std::fs::rename(&source_filepath, &moved_filepath).unwrap();
+
+        //STEP: Move the backup history
+        //RESULT: Both backups should now belong to moved.txt, along with the note
+        let (_, stderr) = cmd_xdg(true, &xdg_data_home, &["-q", "mv", source_filepath.to_str().unwrap(), moved_filepath.to_str().unwrap()]);
+        assert!(stderr.is_empty(), "stderr: {}", stderr);
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+        assert!(tmpfile_exists("moved.txt.bak", function_name!()));
+        assert!(!tmpfile_exists("source.txt.bak9.notes", function_name!()));
+        assert!(tmpfile_exists("moved.txt.bak9.notes", function_name!()));
+        assert!(!tmpfile_exists("moved.txt.bak9.journal", function_name!()));
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[moved_filepath.to_str().unwrap(), "ls"]);
+        assert_eq!(4, stdout.lines().count(), "stdout: {}", stdout);
+        assert!(stdout.contains("first"), "stdout: {}", stdout);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_watch() {
+        open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+
+        //STEP: Watch source.txt
+        //RESULT: An initial backup should be created
+        let mut child = process::Command::new(BIN_EXE)
+            .args(["-q", source_filepath.to_str().unwrap(), "watch", "--debounce", "100"])
+            .spawn()
+            .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+
+        //STEP: Append to source.txt twice in quick succession
+        //RESULT: A single new backup should be created once the writes settle
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_2, function_name!()));
+        assert!(!tmpfile_diff(SOURCE_TXT, SOURCE_TXT_BAK_0, function_name!()));
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_edit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmpdir = open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd(true, &[&source_filepath]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+
+        let editor = tmpdir.join("editor.sh");
+        std::fs::write(&editor, "#!/bin/sh\necho EDITED >> \"$1\"\n").unwrap();
+        std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let edit = |editor: &str| {
+            let output = process::Command::new(BIN_EXE)
+                .env("VISUAL", editor)
+                .args([source_filepath.to_str().unwrap(), "edit"])
+                .output()
+                .unwrap();
+
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        //STEP: Edit source.txt without modifying it
+        //RESULT: The backup made before editing should be discarded, leaving only the original
+        let stdout = edit("true");
+        assert!(stdout.starts_with("No changes"), "stdout: {}", stdout);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+
+        //STEP: Edit source.txt, appending a line
+        //RESULT: The pre-edit backup should be kept and the change shown
+        let stdout = edit(editor.to_str().unwrap());
+        assert!(stdout.contains("EDITED"), "stdout: {}", stdout);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+        assert!(tmpfile_diff(SOURCE_TXT, SOURCE_TXT_BAK_0, function_name!()));
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_exec() {
+        open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let other_filepath = tmpfile_append(TESTING_CONTENT, "other.txt", function_name!());
+        let script = format!("echo CHANGED >> {}", other_filepath.to_str().unwrap());
+
+        //STEP: Run a command that changes only other.txt
+        //RESULT: Both files should be backed up, and only other.txt reported
+        let (stdout, _) = cmd(true, &[source_filepath.to_str().unwrap(), "exec", other_filepath.to_str().unwrap(), "--", "sh", "-c", &script]);
+        assert!(stdout.contains("changed:"), "stdout: {}", stdout);
+        assert!(stdout.contains("other.txt"), "stdout: {}", stdout);
+        assert!(!stdout.contains(SOURCE_TXT), "stdout: {}", stdout);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+        assert!(tmpfile_exists("other.txt.bak", function_name!()));
+
+        //STEP: Run a failing command that changes other.txt, restoring on failure
+        //RESULT: other.txt should be restored to its contents before the command
+        let script = format!("{script}; exit 1");
+        cmd(false, &["-q", other_filepath.to_str().unwrap(), "exec", "--restore-on-failure", "--", "sh", "-c", &script]);
+        assert!(!tmpfile_diff("other.txt", "other.txt.bak.0", function_name!()));
+
+        //STEP: Run a command that changes other.txt, with both files given before exec
+        //RESULT: other.txt should be reported, rather than being taken as DIR
+        let (stdout, _) = cmd(true, &[source_filepath.to_str().unwrap(), other_filepath.to_str().unwrap(), "exec", "--", "sh", "-c", &script.replace("; exit 1", "")]);
+        assert!(stdout.contains("changed:") && stdout.contains("other.txt"), "stdout: {}", stdout);
+        assert!(!stdout.contains(SOURCE_TXT), "stdout: {}", stdout);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_lock() {
+        use bak9::lock::{Contention, Lock};
+
+        let tmpdir = open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let lock = Lock::acquire(&source_filepath, &tmpdir, Contention::Fail, || {}).unwrap();
+
+        //STEP: Backup source.txt while another backup holds the lock, without waiting
+        //RESULT: The backup should fail
+        let (_, stderr) = cmd(false, &["--no-wait", source_filepath.to_str().unwrap()]);
+        assert!(stderr.contains("locked"), "stderr: {}", stderr);
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+
+        //STEP: Backup source.txt while another backup holds the lock, waiting for it
+        //RESULT: The backup should only be created once the lock is released
+        let child = process::Command::new(BIN_EXE)
+            .args(["-q", source_filepath.to_str().unwrap()])
+            .spawn()
+            .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+
+        drop(lock);
+        assert!(child.wait_with_output().unwrap().status.success());
+        assert!(tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+        assert!(!tmpfile_exists("source.txt.bak9.lock", function_name!()));
+
+        close_tmpdir(function_name!());
+    }
+
+    #[named]
+    #[test]
+    fn test_dry_run() {
+        open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd(true, &[&source_filepath]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd(true, &[&source_filepath]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+
+        //STEP: Dry run a backup that prunes down to a single backup
+        //RESULT: Every backup should be listed for deletion, but nothing should change
+        let (stdout, stderr) = cmd(true, &["--dry-run", "-n", "1", source_filepath.to_str().unwrap()]);
+        assert!(stderr.is_empty(), "stderr: {}", stderr);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(3, lines.len(), "stdout: {}", stdout);
+        assert!(lines[0].contains("delete") && lines[0].ends_with(SOURCE_TXT_BAK_0), "stdout: {}", stdout);
+        assert!(lines[1].contains("delete") && lines[1].ends_with(SOURCE_TXT_BAK_1), "stdout: {}", stdout);
+        assert!(lines[2].contains("copy") && lines[2].ends_with(SOURCE_TXT_BAK), "stdout: {}", stdout);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+
+        //STEP: Dry run deleting the backups
+        //RESULT: Both backups should be listed, but still exist
+        let (stdout, _) = cmd(true, &[source_filepath.to_str().unwrap(), "rm", "--dry-run"]);
+        assert_eq!(2, stdout.lines().count(), "stdout: {}", stdout);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_dry_run_gc_restore() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+        let orphan_filepath = tmpfile_append(TESTING_CONTENT, "orphan.txt", function_name!());
+
+        //PREP: Backup source.txt beside it and then change it, and orphan a mirrored backup
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd_xdg(true, &xdg_data_home, &[orphan_filepath.to_str().unwrap(), "-"]);
+        std::fs::remove_file(&orphan_filepath).unwrap();
+        let contents = std::fs::read_to_string(&source_filepath).unwrap();
+
+        //STEP: Dry run restoring the backup
+        //RESULT: The copy should be listed, but source.txt should be unchanged
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["-f", source, "restore", "0", "--dry-run"]);
+        assert!(stdout.contains("copy") && stdout.trim_end().ends_with(SOURCE_TXT), "stdout: {}", stdout);
+        assert_eq!(contents, std::fs::read_to_string(&source_filepath).unwrap());
+
+        //STEP: Dry run collecting garbage
+        //RESULT: The orphaned backup should be listed for deletion, but still exist
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["-f", "gc", "--dry-run"]);
+        assert!(stdout.lines().any(|line| line.contains("delete") && line.ends_with("orphan.txt.bak")), "stdout: {}", stdout);
+        let mirror_dir = xdg_data_home.join("bak9").join(tmpdir.strip_prefix("/").unwrap());
+        assert!(mirror_dir.join("orphan.txt.bak").is_file());
+
+        //STEP: Dry run editing source.txt
+        //RESULT: It should be refused
+        let (_, stderr) = cmd_xdg(false, &xdg_data_home, &[source, "edit", "--dry-run"]);
+        assert!(stderr.contains("--dry-run"), "stderr: {}", stderr);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_dry_run_fallback() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let readonly_dir = tmpdir.join("readonly");
+        std::fs::create_dir(&readonly_dir).unwrap();
+        std::fs::set_permissions(&readonly_dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        //STEP: Dry run a backup into a DIR that can't be written to
+        //RESULT: The copy to the fallback mirror should be listed, but nothing should change
+        // root can write to the directory anyway, so there is no fallback to show
+        if std::fs::write(readonly_dir.join("probe"), "").is_err() {
+            let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["--dry-run", source_filepath.to_str().unwrap(), readonly_dir.to_str().unwrap()]);
+            let mirror_dir = xdg_data_home.join("bak9").join(tmpdir.strip_prefix("/").unwrap());
+            assert_eq!(1, stdout.lines().count(), "stdout: {}", stdout);
+            assert!(stdout.contains("copy") && stdout.trim_end().ends_with(mirror_dir.join(SOURCE_TXT_BAK).to_str().unwrap()), "stdout: {}", stdout);
+            assert!(!mirror_dir.exists());
+        }
+
+        std::fs::set_permissions(&readonly_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_trash() {
+        open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let trash_dir = xdg_data_home.join("Trash");
+
+        //STEP: Backup source.txt three times, keeping at most two and trashing the pruned backup
+        //RESULT: The oldest backup should be in the trash
+        cmd_xdg(true, &xdg_data_home, &[&source_filepath]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd_xdg(true, &xdg_data_home, &[&source_filepath]);
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd_xdg(true, &xdg_data_home, &["--trash", "-n", "2", source_filepath.to_str().unwrap()]);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_2, function_name!()));
+        assert!(trash_dir.join("files").join(SOURCE_TXT_BAK_1).is_file());
+        assert!(trash_dir.join("info").join("source.txt.bak.1.trashinfo").is_file());
+
+        //STEP: Delete the backups
+        //RESULT: They should be moved into the trash, rather than deleted
+        cmd_xdg(true, &xdg_data_home, &["-qf", source_filepath.to_str().unwrap(), "rm"]);
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(trash_dir.join("files").join(SOURCE_TXT_BAK_0).is_file());
+        assert!(trash_dir.join("files").join("source.txt.bak.1.2").is_file());
+
+        //STEP: Backup source.txt into an archive, trashing pruned backups
+        //RESULT: It should be refused, since pruned members are removed from within the archive
+        let (_, stderr) = cmd_xdg(false, &xdg_data_home, &["--trash", "--archive", "tar", source_filepath.to_str().unwrap()]);
+        assert!(stderr.contains("--trash"), "stderr: {}", stderr);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_encrypt() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let identity_filepath = tmpdir.join("key.txt");
+        std::fs::write(&identity_filepath, age::secrecy::ExposeSecret::expose_secret(&identity.to_string())).unwrap();
+        let identity_file = identity_filepath.to_str().unwrap();
+
+        //STEP: Backup source.txt encrypted, twice without changes
+        //RESULT: A single backup that doesn't contain the plaintext
+        cmd_xdg(true, &xdg_data_home, &["--encrypt", &recipient, source]);
+        cmd_xdg(true, &xdg_data_home, &["--encrypt", &recipient, source]);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK, function_name!()));
+        assert!(!tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        let encrypted = std::fs::read(tmpdir.join(SOURCE_TXT_BAK)).unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/"));
+        assert!(!String::from_utf8_lossy(&encrypted).contains(TESTING_CONTENT));
+
+        //STEP: Change source.txt and back it up again
+        //RESULT: The backups are rotated
+        tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        cmd_xdg(true, &xdg_data_home, &["--encrypt", &recipient, source]);
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_0, function_name!()));
+        assert!(tmpfile_exists(SOURCE_TXT_BAK_1, function_name!()));
+
+        //STEP: Diff with and without the identity
+        //RESULT: Decrypting requires the identity
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["--identity", identity_file, source, "diff", "0"]);
+        assert_eq!("No difference", stdout.trim());
+        cmd_xdg(false, &xdg_data_home, &[source, "diff", "0"]);
+
+        //STEP: Restore the first backup
+        //RESULT: source.txt has its original contents
+        cmd_xdg(true, &xdg_data_home, &["-f", source, "restore", "1", "--identity", identity_file]);
+        assert_eq!(TESTING_CONTENT, std::fs::read_to_string(&source_filepath).unwrap().trim());
+
+        //STEP: Backup a file that looks encrypted, twice without changes and without --encrypt
+        //RESULT: A single plain backup, which is shown without the identity
+        let age_filepath = tmpdir.join("secrets.age");
+        std::fs::write(&age_filepath, "age-encryption.org/v1\n").unwrap();
+        let age_file = age_filepath.to_str().unwrap();
+        cmd_xdg(true, &xdg_data_home, &[age_file]);
+        cmd_xdg(true, &xdg_data_home, &[age_file]);
+        assert!(tmpfile_exists("secrets.age.bak", function_name!()));
+        assert!(!tmpfile_exists("secrets.age.bak.0", function_name!()));
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[age_file, "show"]);
+        assert_eq!("age-encryption.org/v1\n", stdout);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_verify() {
+        use std::os::unix::fs::PermissionsExt;
+
+        open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        std::fs::set_permissions(&source_filepath, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        //STEP: Backup a private source.txt beside it and to the mirror
+        //RESULT: Neither backup nor the mirror directory are accessible by other users
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        cmd_xdg(true, &xdg_data_home, &[source, "-"]);
+        let bak_filepath = source_filepath.with_file_name(SOURCE_TXT_BAK);
+        assert_eq!(0o600, mode(&bak_filepath));
+
+        let app_data_dir = xdg_data_home.join("bak9");
+        let mirror_dir = app_data_dir.join(source_filepath.parent().unwrap().strip_prefix("/").unwrap());
+        assert_eq!(0o600, mode(&mirror_dir.join(SOURCE_TXT_BAK)));
+        assert_eq!(0o700, mode(&mirror_dir));
+        assert_eq!(0o700, mode(&app_data_dir));
+
+        let (stdout, stderr) = cmd_xdg(true, &xdg_data_home, &[source, "verify"]);
+        assert_eq!("Verified 2 backups", stdout.trim());
+        assert!(stderr.is_empty(), "stderr: {}", stderr);
+
+        //STEP: Make the sibling backup readable by everyone
+        //RESULT: Verify warns about it, and fails
+        std::fs::set_permissions(&bak_filepath, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let (_, stderr) = cmd_xdg(false, &xdg_data_home, &[source, "verify"]);
+        assert!(stderr.contains("is more permissive than"), "stderr: {}", stderr);
+        assert!(stderr.contains("(0644 > 0600)"), "stderr: {}", stderr);
+        assert!(stderr.contains("Verification failed with 1 warning"), "stderr: {}", stderr);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(unix)]
+    #[named]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let tmpdir = open_tmpdir(function_name!());
+        let target_dir = open_tmpdir_topic("target", function_name!());
+        let target_filepath = target_dir.join(SOURCE_TXT);
+        std::fs::write(&target_filepath, TESTING_CONTENT).unwrap();
+
+        let link_filepath = tmpdir.join("link.txt");
+        let link = link_filepath.to_str().unwrap();
+        symlink(&target_filepath, &link_filepath).unwrap();
+
+        //STEP: Backup the link, following it by default
+        //RESULT: The target is backed up beside itself, with a notice
+        let (_, stderr) = cmd(true, &[link]);
+        assert!(stderr.contains("is a symlink, following it to"), "stderr: {}", stderr);
+        assert!(target_dir.join(SOURCE_TXT_BAK).is_file());
+        assert!(!tmpdir.join("link.txt.bak").exists());
+
+        //STEP: Backup the target beside the link, and then restore it after changing it
+        //RESULT: The backup is a copy of the target, and restoring keeps the link
+        cmd(true, &["--beside-link", link]);
+        let bak_filepath = tmpdir.join("link.txt.bak");
+        assert!(!bak_filepath.is_symlink());
+        assert_eq!(TESTING_CONTENT, std::fs::read_to_string(&bak_filepath).unwrap());
+
+        std::fs::write(&target_filepath, "CHANGED").unwrap();
+        cmd(true, &["--beside-link", "-f", link, "restore"]);
+        assert!(link_filepath.is_symlink());
+        assert_eq!(TESTING_CONTENT, std::fs::read_to_string(&target_filepath).unwrap());
+        std::fs::remove_file(&bak_filepath).unwrap();
+
+        //STEP: Backup the link itself, twice, and then again after pointing it elsewhere
+        //RESULT: The backups are links to each target
+        cmd(true, &["--no-dereference", link]);
+        cmd(true, &["--no-dereference", link]);
+        assert_eq!(target_filepath, std::fs::read_link(&bak_filepath).unwrap());
+        assert!(!tmpdir.join("link.txt.bak.0").exists());
+
+        let other_filepath = target_dir.join("other.txt");
+        std::fs::write(&other_filepath, TESTING_CONTENT).unwrap();
+        std::fs::remove_file(&link_filepath).unwrap();
+        symlink(&other_filepath, &link_filepath).unwrap();
+        cmd(true, &["--no-dereference", link]);
+        assert_eq!(other_filepath, std::fs::read_link(tmpdir.join("link.txt.bak.0")).unwrap());
+        assert_eq!(target_filepath, std::fs::read_link(tmpdir.join("link.txt.bak.1")).unwrap());
+
+        //STEP: Restore the older link
+        //RESULT: FILE is a link to the original target again
+        cmd(true, &["--no-dereference", "-f", link, "restore", "1"]);
+        assert_eq!(target_filepath, std::fs::read_link(&link_filepath).unwrap());
+
+        //STEP: Backup a relative link beside itself, and into the mirror
+        //RESULT: The sibling backup keeps the relative target, while the mirrored one points to the same file
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let relative_filepath = tmpdir.join("relative.txt");
+        let relative = relative_filepath.to_str().unwrap();
+        symlink(SOURCE_TXT_BAK, &relative_filepath).unwrap();
+        std::fs::write(tmpdir.join(SOURCE_TXT_BAK), TESTING_CONTENT).unwrap();
+        cmd(true, &["--no-dereference", relative]);
+        assert_eq!(Path::new(SOURCE_TXT_BAK), std::fs::read_link(tmpdir.join("relative.txt.bak")).unwrap());
+
+        cmd_xdg(true, &xdg_data_home, &["--no-dereference", relative, "-"]);
+        let mirror_dir = xdg_data_home.join("bak9").join(tmpdir.strip_prefix("/").unwrap());
+        assert_eq!(tmpdir.join(SOURCE_TXT_BAK), std::fs::read_link(mirror_dir.join("relative.txt.bak")).unwrap());
+        assert_eq!(TESTING_CONTENT, std::fs::read_to_string(mirror_dir.join("relative.txt.bak")).unwrap());
+
+        close_tmpdir(function_name!());
+    }
+
+    fn cmd_stdin<S: AsRef<std::ffi::OsStr>>(success: bool, xdg_data_home: &Path, stdin: &str, args: &[S]) -> (String, String) {
+        use std::io::Write;
+
+        let mut child = process::Command::new(BIN_EXE)
+            .env("XDG_DATA_HOME", xdg_data_home)
+            .args(args)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // a failing command may exit before reading stdin
+        let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(success, output.status.success());
+        (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_stdin() {
+        open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let stdin_dir = xdg_data_home.join("bak9").join("@stdin");
+
+        //STEP: Backup stdin without a name, and a name without stdin
+        //RESULT: Both are refused
+        cmd_stdin(false, &xdg_data_home, TESTING_CONTENT, &["-"]);
+        cmd_stdin(false, &xdg_data_home, TESTING_CONTENT, &["--name", "crontab", "Cargo.toml"]);
+
+        //STEP: Backup stdin twice, then once more after it has changed
+        //RESULT: The unchanged copy is skipped, and the backups rotate
+        cmd_stdin(true, &xdg_data_home, TESTING_CONTENT, &["--name", "crontab", "-"]);
+        cmd_stdin(true, &xdg_data_home, TESTING_CONTENT, &["--name", "crontab", "-"]);
+        assert!(stdin_dir.join("crontab.bak").is_file());
+        assert!(!stdin_dir.join("crontab.bak.0").exists());
+
+        cmd_stdin(true, &xdg_data_home, "CHANGED", &["--name", "crontab", "-"]);
+        assert!(stdin_dir.join("crontab.bak.0").is_file());
+        assert!(stdin_dir.join("crontab.bak.1").is_file());
+        assert!(!stdin_dir.join("crontab").exists());
+
+        //STEP: List the backups and compare stdin with the latest one
+        //RESULT: Both backups are listed, and stdin differs
+        let (stdout, _) = cmd_stdin(true, &xdg_data_home, "", &["--name", "crontab", "-", "ls"]);
+        assert!(stdout.contains("crontab.bak.0") && stdout.contains("crontab.bak.1"), "stdout: {}", stdout);
+        let (stdout, _) = cmd_stdin(true, &xdg_data_home, TESTING_CONTENT, &["--name", "crontab", "-", "diff"]);
+        assert!(stdout.contains("CHANGED"), "stdout: {}", stdout);
+
+        //STEP: Restore the older backup
+        //RESULT: It's written to stdout
+        let (stdout, _) = cmd_stdin(true, &xdg_data_home, "", &["--name", "crontab", "-", "restore", "1"]);
+        assert_eq!(TESTING_CONTENT, stdout);
+
+        //STEP: Backup stdin while another backup of the same name holds the lock, without waiting
+        //RESULT: It fails before staging stdin over the other's copy
+        std::fs::write(stdin_dir.join("crontab"), "OTHER").unwrap();
+        let lock_file = std::fs::File::create(stdin_dir.join("crontab.bak9.lock")).unwrap();
+        lock_file.lock().unwrap();
+        cmd_stdin(false, &xdg_data_home, "NEW", &["--no-wait", "--name", "crontab", "-"]);
+        assert_eq!("OTHER", std::fs::read_to_string(stdin_dir.join("crontab")).unwrap());
+        drop(lock_file);
+        std::fs::remove_file(stdin_dir.join("crontab.bak9.lock")).unwrap();
+
+        //STEP: List the backups, with a copy of stdin left behind by an interrupted run
+        //RESULT: The copy is deleted
+        cmd_stdin(true, &xdg_data_home, "", &["--name", "crontab", "-", "ls"]);
+        assert!(!stdin_dir.join("crontab").exists());
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_show() {
+        open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append(TESTING_CONTENT, SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+
+        //STEP: Backup two versions of source.txt beside it, into an archive and to the mirror
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        cmd_xdg(true, &xdg_data_home, &["--archive", "tar.gz", source]);
+        cmd_xdg(true, &xdg_data_home, &[source, "-"]);
+        std::fs::write(&source_filepath, "CHANGED").unwrap();
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        cmd_xdg(true, &xdg_data_home, &["--archive", "tar.gz", source]);
+        cmd_xdg(true, &xdg_data_home, &[source, "-"]);
+
+        //STEP: Show each version in each location
+        //RESULT: Their contents are written to stdout
+        for args in [&[][..], &["--archive", "tar.gz"][..]] {
+            let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[args, &[source, "show", "1"]].concat());
+            assert_eq!(TESTING_CONTENT, stdout.trim());
+            let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[args, &[source, "cat"]].concat());
+            assert_eq!("CHANGED", stdout);
+        }
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "-", "show", "1"]);
+        assert_eq!(TESTING_CONTENT, stdout.trim());
+
+        //STEP: Show a backup that doesn't exist
+        //RESULT: Failure
+        cmd_xdg(false, &xdg_data_home, &[source, "show", "5"]);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_grep() {
+        open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append("listen 80;", SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+
+        //STEP: Backup versions that add and then remove a line, beside source.txt and to the mirror
+        for contents in ["listen 80;", "listen 80;\nssl on;", "listen 443;\nssl on;", "listen 443;"] {
+            std::fs::write(&source_filepath, contents).unwrap();
+            cmd_xdg(true, &xdg_data_home, &[source]);
+            cmd_xdg(true, &xdg_data_home, &["--archive", "tar.gz", source]);
+        }
+        cmd_xdg(true, &xdg_data_home, &[source, "-"]);
+
+        //STEP: Search for a pattern
+        //RESULT: Every matching line is reported in both locations
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "grep", "^listen 4[0-9]+"]);
+        assert_eq!(3, stdout.lines().filter(|line| line.contains("listen 443;")).count(), "stdout: {}", stdout);
+        assert!(stdout.contains("bak.0") && stdout.contains("1: listen 443;"), "stdout: {}", stdout);
+        assert!(!stdout.contains("listen 80;"), "stdout: {}", stdout);
+
+        //STEP: Bisect for when listening on 443 appeared, and when ssl vanished
+        //RESULT: The versions either side of each change are reported
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "grep", "443", "--first-seen"]);
+        assert!(stdout.contains("first seen in bak.1") && stdout.contains("absent from   bak.2"), "stdout: {}", stdout);
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "grep", "ssl", "--last-seen"]);
+        assert!(stdout.contains("last seen in  bak.1") && stdout.contains("vanished from bak.0"), "stdout: {}", stdout);
+
+        //STEP: Bisect the archived versions
+        //RESULT: The members are streamed, and the same versions are reported
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &["--archive", "tar.gz", source, "grep", "443", "--first-seen"]);
+        assert!(stdout.contains("bak9.tar.gz") && stdout.contains("first seen in bak.1") && stdout.contains("absent from   bak.2"), "stdout: {}", stdout);
+
+        //STEP: Search for an invalid pattern
+        //RESULT: Failure
+        cmd_xdg(false, &xdg_data_home, &[source, "grep", "("]);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_log() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append("a", SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+
+        //STEP: Backup two versions beside source.txt, and then a third to the mirror
+        std::fs::write(&source_filepath, "a\nb\n").unwrap();
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        std::fs::write(&source_filepath, "a\nc\nd\n").unwrap();
+        cmd_xdg(true, &xdg_data_home, &[source]);
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        std::fs::write(&source_filepath, "c\nd\n").unwrap();
+        cmd_xdg(true, &xdg_data_home, &[source, "-"]);
+
+        //STEP: Show the log
+        //RESULT: Every version is listed newest first, with a diffstat against the one before it
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "log"]);
+        let lines: Vec<&str> = stdout.lines().skip(1).collect();
+        assert_eq!(3, lines.len(), "stdout: {}", stdout);
+        assert!(lines[0].contains("bak.0") && lines[0].contains("+0 -1") && lines[0].contains("bak9"), "stdout: {}", stdout);
+        assert!(lines[1].contains("bak.0") && lines[1].contains("+2 -1"), "stdout: {}", stdout);
+        assert!(lines[2].contains("bak.1") && lines[2].contains("+2 -0"), "stdout: {}", stdout);
+
+        //STEP: Show the log with patches
+        //RESULT: The changed lines are shown
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "log", "-p"]);
+        assert!(stdout.contains("        -b") && stdout.contains("        +c") && stdout.contains("        -a"), "stdout: {}", stdout);
+
+        //STEP: Backup a symlink as a link, and then the text file that replaced it
+        //RESULT: The link shows its target, and the text file's lines are all added
+        let link_filepath = tmpdir.join("link.txt");
+        std::os::unix::fs::symlink(&source_filepath, &link_filepath).unwrap();
+        let link = link_filepath.to_str().unwrap();
+        cmd_xdg(true, &xdg_data_home, &["--no-dereference", link]);
+        std::fs::remove_file(&link_filepath).unwrap();
+        std::fs::write(&link_filepath, "x\ny\n").unwrap();
+        cmd_xdg(true, &xdg_data_home, &[link]);
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[link, "log"]);
+        let lines: Vec<&str> = stdout.lines().skip(1).collect();
+        assert!(lines[0].contains("+2 -0"), "stdout: {}", stdout);
+        assert!(lines[1].contains(&format!("-> {source}")), "stdout: {}", stdout);
+
+        close_tmpdir(function_name!());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[named]
+    #[test]
+    fn test_notes() {
+        let tmpdir = open_tmpdir(function_name!());
+        let xdg_data_home = open_tmpdir_topic("xdg", function_name!());
+        let source_filepath = tmpfile_append("listen 80;", SOURCE_TXT, function_name!());
+        let source = source_filepath.to_str().unwrap();
+
+        //STEP: Backup three versions, noting the first two, beside source.txt and into an archive
+        for (contents, note) in [("listen 80;", Some("before TLS")), ("listen 443;", Some("after TLS")), ("listen 8443;", None)] {
+            std::fs::write(&source_filepath, contents).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+            let note_args: &[&str] = match note { Some(note) => &["-m", note], None => &[] };
+            cmd_xdg(true, &xdg_data_home, &[note_args, &[source]].concat());
+            cmd_xdg(true, &xdg_data_home, &[note_args, &["--archive", "tar", source]].concat());
+        }
+        assert!(tmpdir.join("source.txt.bak9.notes").is_file());
+
+        //STEP: List the backups and show the log
+        //RESULT: The notes follow their backups as they are rotated
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "ls"]);
+        assert!(stdout.contains("source.txt.bak.2  before TLS") && stdout.contains("source.txt.bak.1  after TLS"), "stdout: {}", stdout);
+
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "log"]);
+        assert!(stdout.lines().any(|line| line.contains("bak.2") && line.ends_with("before TLS")), "stdout: {}", stdout);
+
+        //STEP: Show, diff and restore backups by their notes
+        //RESULT: The noted backups are selected
+        for args in [&[][..], &["--archive", "tar"][..]] {
+            let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[args, &[source, "show", "@before TLS"]].concat());
+            assert_eq!("listen 80;", stdout);
+            let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[args, &[source, "diff", "@after TLS"]].concat());
+            assert!(stdout.contains("listen 443;"), "stdout: {}", stdout);
+            cmd_xdg(false, &xdg_data_home, &[args, &[source, "show", "@unknown"]].concat());
+        }
+
+        cmd_xdg(true, &xdg_data_home, &["-f", source, "restore", "@before TLS"]);
+        assert_eq!("listen 80;", std::fs::read_to_string(&source_filepath).unwrap());
+
+        //STEP: Backup the restored file, which keeps the modification time of the restored backup
+        //RESULT: Each backup keeps its own note
+        cmd_xdg(true, &xdg_data_home, &["-m", "restored", source]);
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "ls"]);
+        assert!(stdout.contains("source.txt.bak.0  restored") && stdout.contains("source.txt.bak.3  before TLS"), "stdout: {}", stdout);
+
+        //STEP: Backup the unchanged file with a note
+        //RESULT: The note is attached to the latest backup instead
+        let (_, stderr) = cmd_xdg(true, &xdg_data_home, &["-m", "unchanged", source]);
+        assert!(stderr.contains("unchanged"), "stderr: {}", stderr);
+        let (stdout, _) = cmd_xdg(true, &xdg_data_home, &[source, "ls"]);
+        assert!(stdout.contains("source.txt.bak.0  unchanged") && stdout.contains("source.txt.bak.3  before TLS"), "stdout: {}", stdout);
+
+        //STEP: Delete the backups
+        //RESULT: Their notes are deleted with them
+        cmd_xdg(true, &xdg_data_home, &["-f", "--no-trash", source, "rm"]);
+        assert!(!tmpdir.join("source.txt.bak9.notes").exists());
+
+        close_tmpdir(function_name!());
+    }
 }
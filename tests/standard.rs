@@ -1,9 +1,10 @@
+#![allow(clippy::bool_assert_comparison)]
+
 mod common;
 
 #[cfg(test)]
 mod tests {
     use super::common::*;
-    use bak9;
     use clap::Parser;
     use std::path::PathBuf;
 
@@ -14,12 +15,11 @@ mod tests {
 
         std::fs::write(tmpdir.join("no_extension"), "LINE 1").unwrap();
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("no_extension"),
-            dir: None,
+            file: Some(tmpdir.join("no_extension")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("no_extension.bak", function_name!()),
@@ -32,12 +32,11 @@ mod tests {
         let tmpdir = open_tmpdir(function_name!());
 
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("noexist.txt"),
-            dir: None,
+            file: Some(tmpdir.join("noexist.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_err());
 
@@ -46,12 +45,11 @@ mod tests {
 
         std::fs::write(tmpdir.join("source_1.txt"), "LINE 1").unwrap();
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak", function_name!()),
@@ -62,12 +60,11 @@ mod tests {
 
         tmpfile_append("LINE 2", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -81,12 +78,11 @@ mod tests {
 
         tmpfile_append("LINE 3", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -103,12 +99,11 @@ mod tests {
 
         tmpfile_append("LINE 4", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -119,12 +114,11 @@ mod tests {
 
         tmpfile_append("LINE 5", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 2,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -135,12 +129,11 @@ mod tests {
 
         tmpfile_append("LINE 6", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 1,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(false, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -150,12 +143,11 @@ mod tests {
 
         tmpfile_append("LINE 7", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -167,12 +159,13 @@ mod tests {
  
         tmpfile_append("LINE 8", "source_1.txt", function_name!());
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_1.txt"),
-            dir: None,
+            file: Some(tmpdir.join("source_1.txt")),
             num: 3,
             force: true,
             quiet: true,
+            no_trash: true,
             subcommand: Some(bak9::cli::Command::Wipe),
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(false, tmpfile_exists("source_1.txt.bak.0", function_name!()));
@@ -190,12 +183,12 @@ mod tests {
         let topic_tmpdir = open_tmpdir_topic("source_2_dir", function_name!());
         std::fs::write(tmpdir.join("source_2.txt"), "LINE 1").unwrap();
         let result = bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source_2.txt"),
+            file: Some(tmpdir.join("source_2.txt")),
             dir: Some(topic_tmpdir),
             num: 3,
             force: true,
             quiet: true,
-            subcommand: None,
+            ..Default::default()
         });
         assert_eq!(true, result.is_ok());
         assert_eq!(true, tmpfile_topic_exists("source_2.txt.bak", "source_2_dir", function_name!()));
@@ -221,12 +214,14 @@ mod tests {
         assert_eq!(true, mirror_dir.join("source.txt.bak").is_file());
 
         bak9::run_with(bak9::cli::Cli {
-            file: tmpdir.join("source.txt"),
+            file: Some(tmpdir.join("source.txt")),
             dir: Some(PathBuf::from("-")),
             num: 3,
             force: true,
             quiet: true,
+            no_trash: true,
             subcommand: Some(bak9::cli::Command::Wipe),
+            ..Default::default()
         }).unwrap();
 
         assert_eq!(false, mirror_dir.join("source.txt.bak").exists());
@@ -234,4 +229,164 @@ mod tests {
 
         close_tmpdir(function_name!());
     }
-}
\ No newline at end of file
+
+    #[named]
+    #[test]
+    fn test_sibling_dir_store() {
+        use bak9::store::{BackupStore, SiblingDirStore};
+
+        let tmpdir = open_tmpdir(function_name!());
+        let source_filepath = tmpfile_append("LINE 1", "source.txt", function_name!());
+        let store = SiblingDirStore::new(&source_filepath, &tmpdir);
+
+        // left behind by an interrupted backup
+        tmpfile_append("LINE", "source.txt.bak.0.tmp", function_name!());
+        tmpfile_append("LINE", "other.txt.bak.0.tmp", function_name!());
+
+        assert_eq!(Some(tmpdir.join("source.txt.bak")), store.put(3).unwrap());
+        assert_eq!(false, tmpfile_exists("source.txt.bak.0.tmp", function_name!()), "stale temp file should be removed");
+        assert_eq!(true, tmpfile_exists("other.txt.bak.0.tmp", function_name!()));
+        assert_eq!(false, tmpfile_exists("source.txt.bak.tmp", function_name!()));
+        assert_eq!(None, store.put(3).unwrap(), "unchanged source should be skipped");
+
+        tmpfile_append("LINE 2", "source.txt", function_name!());
+        assert_eq!(Some(tmpdir.join("source.txt.bak.0")), store.put(3).unwrap());
+        assert_eq!(vec![tmpdir.join("source.txt.bak.0"), tmpdir.join("source.txt.bak.1")], store.list().unwrap());
+        assert_eq!(tmpdir.join("source.txt.bak.1"), store.get(1).unwrap().to_path_buf());
+        assert_eq!(true, store.get(2).is_err());
+
+        store.discard().unwrap();
+        assert_eq!(vec![tmpdir.join("source.txt.bak")], store.list().unwrap());
+
+        store.delete().unwrap();
+        assert_eq!(true, store.list().unwrap().is_empty());
+
+        close_tmpdir(function_name!());
+    }
+
+    #[named]
+    #[test]
+    fn test_fallback_store() {
+        use bak9::store::{BackupStore, FallbackStore, SiblingDirStore};
+
+        let tmpdir = open_tmpdir(function_name!());
+        let fallback_dir = open_tmpdir_topic("fallback", function_name!());
+        let source_filepath = tmpfile_append("LINE 1", "source.txt", function_name!());
+        let store = FallbackStore::new(
+            Box::new(SiblingDirStore::new(&source_filepath, &tmpdir)),
+            Box::new(SiblingDirStore::new(&source_filepath, &fallback_dir)));
+
+        //STEP: Backup to the fallback only, as if the primary couldn't be written to
+        //RESULT: The backup should be read through the composite store
+        store.fallback().put(3).unwrap();
+        assert_eq!(fallback_dir.join("source.txt.bak"), store.get(0).unwrap().to_path_buf());
+        assert_eq!(true, store.get(1).is_err());
+
+        //STEP: Backup a newer version to the primary
+        //RESULT: Backups should be indexed as listed, the primary's first
+        tmpfile_append("LINE 2", "source.txt", function_name!());
+        store.put(3).unwrap();
+        assert_eq!(vec![tmpdir.join("source.txt.bak"), fallback_dir.join("source.txt.bak")], store.list().unwrap());
+        assert_eq!(fallback_dir.join("source.txt.bak"), store.get(1).unwrap().to_path_buf());
+
+        let mut contents = String::new();
+        store.read(1, &mut |reader| reader.read_to_string(&mut contents).map(|_| ()).map_err(|e| bak9::Error::Generic(e.to_string()))).unwrap();
+        assert_eq!("LINE 1\n", contents);
+
+        store.restore(1, &source_filepath).unwrap();
+        assert_eq!("LINE 1\n", std::fs::read_to_string(&source_filepath).unwrap());
+        assert_eq!(true, store.get(2).is_err());
+
+        close_tmpdir(function_name!());
+    }
+
+    #[named]
+    #[test]
+    fn test_tar_store() {
+        use bak9::store::{Archive, BackupStore, TarStore};
+
+        for archive in [Archive::Tar, Archive::TarGz] {
+            let tmpdir = open_tmpdir(function_name!());
+            let source_filepath = tmpfile_append("LINE 1", "source.txt", function_name!());
+            let store = TarStore::new(&source_filepath, &tmpdir, archive);
+
+            //STEP: Backup three versions, keeping at most two
+            //RESULT: Only a single archive should exist, holding the newest two versions
+            store.put(2).unwrap();
+            assert_eq!(None, store.put(2).unwrap(), "unchanged source should be skipped");
+            tmpfile_append("LINE 2", "source.txt", function_name!());
+            store.put(2).unwrap();
+            tmpfile_append("LINE 3", "source.txt", function_name!());
+            store.put(2).unwrap();
+
+            assert_eq!(true, store.archive_path().is_file());
+            assert_eq!(false, tmpfile_exists("source.txt.bak", function_name!()));
+            assert_eq!(2, store.list().unwrap().len());
+            assert_eq!("LINE 1\nLINE 2\nLINE 3\n", std::fs::read_to_string(store.get(0).unwrap()).unwrap());
+            assert_eq!("LINE 1\nLINE 2\n", std::fs::read_to_string(store.get(1).unwrap()).unwrap());
+            assert_eq!(true, store.get(2).is_err());
+
+            //STEP: Retrieve and then drop a version
+            //RESULT: The extracted copy should be deleted
+            let extracted = store.get(0).unwrap().to_path_buf();
+            assert_eq!(false, extracted.exists());
+
+            //STEP: Stream a version
+            //RESULT: Its contents should be read without extracting it
+            let mut contents = String::new();
+            store.read(1, &mut |reader| reader.read_to_string(&mut contents).map(|_| ()).map_err(|e| bak9::Error::Generic(e.to_string()))).unwrap();
+            assert_eq!("LINE 1\nLINE 2\n", contents);
+
+            //STEP: Restore the older version
+            //RESULT: The source file should match it
+            store.restore(1, &source_filepath).unwrap();
+            assert_eq!("LINE 1\nLINE 2\n", std::fs::read_to_string(&source_filepath).unwrap());
+
+            //STEP: Discard the newest version
+            //RESULT: Only the older version should remain
+            store.discard().unwrap();
+            assert_eq!(1, store.list().unwrap().len());
+            assert_eq!("LINE 1\nLINE 2\n", std::fs::read_to_string(store.get(0).unwrap()).unwrap());
+
+            //STEP: Fail to append a version that would prune the older one
+            //RESULT: The older version should remain, without any temporary files
+            let encrypted_store = TarStore::new(&source_filepath, &tmpdir, archive)
+                .with_encryption(Some(bak9::crypt::Encryption::Passphrase));
+            tmpfile_append("LINE 4", "source.txt", function_name!());
+            assert_eq!(true, encrypted_store.put(1).is_err(), "{} should not be set", bak9::crypt::ENV_BAK9_PASSPHRASE);
+            assert_eq!(1, store.list().unwrap().len());
+            assert_eq!(2, std::fs::read_dir(&tmpdir).unwrap().count(), "only source.txt and the archive should exist");
+
+            store.delete().unwrap();
+            assert_eq!(false, store.archive_path().exists());
+
+            close_tmpdir(function_name!());
+        }
+    }
+
+    #[named]
+    #[test]
+    fn test_mirror_walk() {
+        use bak9::store::{Archive, BackupStore, MirrorStore};
+
+        let tmpdir = open_tmpdir(function_name!());
+        let app_data_dir = open_tmpdir_topic("app_data", function_name!());
+        let source_1 = tmpfile_append("LINE 1", "source_1.txt", function_name!());
+        let source_2 = tmpfile_append("LINE 1", "source_2.txt", function_name!());
+
+        MirrorStore::new(&source_1, &app_data_dir).put(3).unwrap();
+        tmpfile_append("LINE 2", "source_1.txt", function_name!());
+        MirrorStore::new(&source_1, &app_data_dir).put(3).unwrap();
+        MirrorStore::new(&source_2, &app_data_dir).with_archive(Some(Archive::Tar)).put(3).unwrap();
+
+        let sources = bak9::mirror::walk(&app_data_dir).unwrap();
+        assert_eq!(2, sources.len());
+        assert_eq!(tmpdir.join("source_1.txt"), sources[0].source_file);
+        assert_eq!(2, sources[0].summary().unwrap().count);
+        assert_eq!(tmpdir.join("source_2.txt"), sources[1].source_file);
+        assert_eq!(1, sources[1].summary().unwrap().count);
+        assert_eq!(false, sources[0].is_orphan());
+
+        close_tmpdir(function_name!());
+    }
+}